@@ -0,0 +1,291 @@
+//! Chapters of Matroska files.
+//!
+//! They are in the segment's `Chapters` element, which muxers put either
+//! before the audio or after it with a `SeekHead` entry pointing to them.
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use super::{invalid, Chapter};
+
+const EBML: u32 = 0x1A45DFA3;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const CLUSTER: u32 = 0x1F43B675;
+const CHAPTERS: u32 = 0x1043A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const EDITION_FLAG_DEFAULT: u32 = 0x45DB;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+/// Chapter times are in nanoseconds
+const NANOSECONDS: f64 = 1e9;
+
+pub fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Chapter>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let (id, size) = header(reader)?;
+    if id != EBML {
+        return Ok(vec![]);
+    }
+    reader.seek(SeekFrom::Current(size.unwrap_or(0) as i64))?;
+
+    let (id, size) = header(reader)?;
+    if id != SEGMENT {
+        return Err(invalid("Matroska file has no segment"));
+    }
+    let segment = reader.stream_position()?;
+    let end = size.map_or(len, |size| (segment + size).min(len));
+
+    // Everything before the audio is read through, and the audio skipped
+    // over with the help of the seek head
+    let mut seeks = vec![];
+    let mut position = segment;
+    while position < end {
+        reader.seek(SeekFrom::Start(position))?;
+        let (id, size) = header(reader)?;
+        let start = reader.stream_position()?;
+        match id {
+            CHAPTERS => return read_chapters(&body(reader, size)?),
+            SEEK_HEAD => seeks.extend(chapter_seeks(&body(reader, size)?)?),
+            CLUSTER => break,
+            _ => {}
+        }
+        let Some(size) = size else {
+            break;
+        };
+        position = start + size;
+    }
+
+    for offset in seeks {
+        reader.seek(SeekFrom::Start(segment + offset))?;
+        let (id, size) = header(reader)?;
+        if id == CHAPTERS {
+            return read_chapters(&body(reader, size)?);
+        }
+    }
+    Ok(vec![])
+}
+
+/// Where the seek head says chapters are, from the start of the segment
+fn chapter_seeks(seek_head: &[u8]) -> io::Result<Vec<u64>> {
+    let mut seeks = vec![];
+    for (id, seek) in children(seek_head)? {
+        if id != SEEK {
+            continue;
+        }
+        let fields = children(seek)?;
+        let field = |wanted| {
+            fields
+                .iter()
+                .find(|(id, _)| *id == wanted)
+                .map(|(_, f)| uint(f))
+        };
+        if field(SEEK_ID) == Some(CHAPTERS as u64) {
+            seeks.extend(field(SEEK_POSITION));
+        }
+    }
+    Ok(seeks)
+}
+
+/// The chapters of the default edition, or of the first if none is
+fn read_chapters(chapters: &[u8]) -> io::Result<Vec<Chapter>> {
+    let mut editions = vec![];
+    for (id, edition) in children(chapters)? {
+        if id == EDITION_ENTRY {
+            editions.push(children(edition)?);
+        }
+    }
+    let is_default = |edition: &&Vec<(u32, &[u8])>| {
+        edition
+            .iter()
+            .any(|(id, flag)| *id == EDITION_FLAG_DEFAULT && uint(flag) == 1)
+    };
+    let Some(edition) = editions.iter().find(is_default).or(editions.first()) else {
+        return Ok(vec![]);
+    };
+
+    let mut chapters = vec![];
+    for (id, atom) in edition {
+        if *id != CHAPTER_ATOM {
+            continue;
+        }
+        let mut start = None;
+        let mut title = None;
+        let mut hidden = false;
+        for (id, field) in children(atom)? {
+            match id {
+                CHAPTER_TIME_START => start = Some(uint(field) as f64 / NANOSECONDS),
+                CHAPTER_FLAG_HIDDEN => hidden = uint(field) == 1,
+                // The first language's title
+                CHAPTER_DISPLAY if title.is_none() => {
+                    title = children(field)?
+                        .into_iter()
+                        .find(|(id, _)| *id == CHAP_STRING)
+                        .map(|(_, s)| String::from_utf8_lossy(s).trim().to_string())
+                        .filter(|s| !s.is_empty());
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = start.filter(|_| !hidden) {
+            chapters.push(Chapter { title, start });
+        }
+    }
+    Ok(chapters)
+}
+
+/// An element's id and the size of its body, which is unknown for some that
+/// are written as they go
+fn header<R: Read>(reader: &mut R) -> io::Result<(u32, Option<u64>)> {
+    let (id, _) = vint(reader, true)?;
+    let (size, len) = vint(reader, false)?;
+    let unknown = size == (1 << (7 * len)) - 1;
+    Ok((id as u32, (!unknown).then_some(size)))
+}
+
+/// A variable length integer, whose leading zeros say how many bytes follow.
+/// Ids keep the marker bit after them, sizes don't.
+fn vint<R: Read>(reader: &mut R, keep_marker: bool) -> io::Result<(u64, u32)> {
+    let mut first = [0];
+    reader.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() + 1;
+    if len > 8 {
+        return Err(invalid("Invalid Matroska number"));
+    }
+
+    let mut value = if keep_marker {
+        first[0] as u64
+    } else {
+        first[0] as u64 & (0xff >> len)
+    };
+    for _ in 1..len {
+        reader.read_exact(&mut first)?;
+        value = (value << 8) | first[0] as u64;
+    }
+    Ok((value, len))
+}
+
+fn body<R: Read>(reader: &mut R, size: Option<u64>) -> io::Result<Vec<u8>> {
+    let size = size.ok_or_else(|| invalid("Matroska element has no size"))?;
+    let mut body = vec![];
+    reader.take(size).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+/// The elements in an element's body
+fn children(body: &[u8]) -> io::Result<Vec<(u32, &[u8])>> {
+    let mut cursor = Cursor::new(body);
+    let mut elements = vec![];
+    while (cursor.position() as usize) < body.len() {
+        let (id, size) = header(&mut cursor)?;
+        let start = cursor.position() as usize;
+        let end = size.map_or(body.len(), |size| start.saturating_add(size as usize));
+        let element = body
+            .get(start..end)
+            .ok_or_else(|| invalid("Matroska element runs past its parent"))?;
+        elements.push((id, element));
+        cursor.set_position(end as u64);
+    }
+    Ok(elements)
+}
+
+fn uint(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut element: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&byte| byte == 0)
+            .collect();
+        // Sizes are always 8 bytes long here
+        element.push(1);
+        element.extend(&(body.len() as u64).to_be_bytes()[1..]);
+        element.extend(body);
+        element
+    }
+
+    fn atom(start: u64, title: &str, hidden: bool) -> Vec<u8> {
+        let display = element(CHAPTER_DISPLAY, &element(CHAP_STRING, title.as_bytes()));
+        let fields = [
+            element(CHAPTER_TIME_START, &start.to_be_bytes()),
+            element(CHAPTER_FLAG_HIDDEN, &[hidden as u8]),
+            display,
+        ];
+        element(CHAPTER_ATOM, &fields.concat())
+    }
+
+    fn chapters() -> Vec<u8> {
+        let first = element(EDITION_ENTRY, &atom(0, "Other", false));
+        let default = [
+            element(EDITION_FLAG_DEFAULT, &[1]),
+            atom(0, "One", false),
+            atom(500_000_000, "Hidden", true),
+            atom(90_000_000_000, "Two", false),
+        ];
+        let default = element(EDITION_ENTRY, &default.concat());
+        element(CHAPTERS, &[first, default].concat())
+    }
+
+    fn file(segment: &[u8]) -> Vec<u8> {
+        [element(EBML, &[]), element(SEGMENT, segment)].concat()
+    }
+
+    fn expected() -> Vec<Chapter> {
+        vec![
+            Chapter {
+                title: Some("One".to_string()),
+                start: 0.0,
+            },
+            Chapter {
+                title: Some("Two".to_string()),
+                start: 90.0,
+            },
+        ]
+    }
+
+    #[test]
+    fn reads_chapters_before_audio() {
+        let segment = [chapters(), element(CLUSTER, &[0; 64])].concat();
+        let chapters = read(&mut Cursor::new(file(&segment))).unwrap();
+        assert_eq!(chapters, expected());
+    }
+
+    #[test]
+    fn follows_seek_head_past_audio() {
+        // The seek head is the same length whatever position it holds
+        let seek_head = |position: u64| {
+            let seek = [
+                element(SEEK_ID, &CHAPTERS.to_be_bytes()),
+                element(SEEK_POSITION, &position.to_be_bytes()),
+            ];
+            element(SEEK_HEAD, &element(SEEK, &seek.concat()))
+        };
+        let cluster = element(CLUSTER, &[0; 64]);
+        let position = (seek_head(0).len() + cluster.len()) as u64;
+        let segment = [seek_head(position), cluster, chapters()].concat();
+
+        let chapters = read(&mut Cursor::new(file(&segment))).unwrap();
+        assert_eq!(chapters, expected());
+    }
+
+    #[test]
+    fn unknown_sizes() {
+        let mut cursor = Cursor::new([0x1f, 0x43, 0xb6, 0x75, 0xff]);
+        assert_eq!(header(&mut cursor).unwrap(), (CLUSTER, None));
+        let mut cursor = Cursor::new([0x1f, 0x43, 0xb6, 0x75, 0x40, 0x02]);
+        assert_eq!(header(&mut cursor).unwrap(), (CLUSTER, Some(2)));
+    }
+}
@@ -0,0 +1,171 @@
+//! Chapters within a song, e.g. of an audiobook.
+//!
+//! They come from a FLAC file's cuesheet, from Vorbis comments in the form
+//! `CHAPTER001=00:00:00.000` and `CHAPTER001NAME=Title`, or from the chapters
+//! of MP4 and Matroska files, which symphonia doesn't read.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    str::FromStr,
+};
+
+use log::warn;
+
+use crate::error::PlayerError;
+
+mod mkv;
+mod mp4;
+
+/// Going back further than this into a chapter starts it over instead
+const PREVIOUS_GRACE: f64 = 3.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: Option<String>,
+    /// Where the chapter starts in the song, in seconds
+    pub start: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChapterChange {
+    Next,
+    Previous,
+    /// Counted from 1
+    Number(usize),
+}
+
+impl FromStr for ChapterChange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next" => Ok(Self::Next),
+            "prev" | "previous" => Ok(Self::Previous),
+            n => match n.parse() {
+                Ok(0) | Err(_) => Err(format!("Expected next, prev or a chapter number: {s}")),
+                Ok(n) => Ok(Self::Number(n)),
+            },
+        }
+    }
+}
+
+/// Collects chapters from Vorbis comments
+#[derive(Default)]
+pub struct VorbisChapters(BTreeMap<u32, (Option<f64>, Option<String>)>);
+
+impl VorbisChapters {
+    /// Takes the tag if it is about a chapter
+    pub fn read_tag(&mut self, key: &str, value: &str) -> bool {
+        let Some(rest) = key
+            .get(..7)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("CHAPTER"))
+            .map(|_| &key[7..])
+        else {
+            return false;
+        };
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let Ok(number) = rest[..digits].parse() else {
+            return false;
+        };
+
+        let chapter = self.0.entry(number).or_default();
+        match &rest[digits..] {
+            "" => chapter.0 = parse_time(value),
+            name if name.eq_ignore_ascii_case("NAME") => chapter.1 = Some(value.to_string()),
+            // e.g. CHAPTER001URL
+            _ => {}
+        }
+        true
+    }
+
+    pub fn into_chapters(self) -> Vec<Chapter> {
+        self.0
+            .into_values()
+            .filter_map(|(start, title)| {
+                Some(Chapter {
+                    title,
+                    start: start?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Reads the chapters of an MP4 or Matroska file, telling them apart by their
+/// first bytes. Anything else has none.
+pub fn read_container(path: &Path) -> Vec<Chapter> {
+    let read = || -> io::Result<Vec<Chapter>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        if reader.read_exact(&mut magic).is_err() {
+            return Ok(vec![]);
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        match magic {
+            [_, _, _, _, b'f', b't', b'y', b'p'] => mp4::read(&mut reader),
+            [0x1a, 0x45, 0xdf, 0xa3, ..] => mkv::read(&mut reader),
+            _ => Ok(vec![]),
+        }
+    };
+
+    read().unwrap_or_else(|e| {
+        warn!("Failed to read the chapters of {}: {e}", path.display());
+        vec![]
+    })
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Parses `HH:MM:SS.mmm`
+fn parse_time(s: &str) -> Option<f64> {
+    let mut parts = s.trim().rsplitn(3, ':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next().map_or(Ok(0.0), str::parse).ok()?;
+    let hours: f64 = parts.next().map_or(Ok(0.0), str::parse).ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// The index of the chapter playing at `position`
+pub fn current(chapters: &[Chapter], position: f64) -> Option<usize> {
+    chapters.iter().rposition(|c| c.start <= position)
+}
+
+/// Where to seek to for a change of chapter, in seconds
+pub fn target(
+    chapters: &[Chapter],
+    position: f64,
+    change: ChapterChange,
+) -> Result<f64, PlayerError> {
+    if chapters.is_empty() {
+        return Err(PlayerError::Queue("The song has no chapters".to_string()));
+    }
+    let index = match (change, current(chapters, position)) {
+        (ChapterChange::Number(n), _) => n - 1,
+        (ChapterChange::Next, Some(i)) => i + 1,
+        (ChapterChange::Next, None) => 0,
+        (ChapterChange::Previous, Some(i)) if position - chapters[i].start > PREVIOUS_GRACE => i,
+        (ChapterChange::Previous, Some(i)) => i.saturating_sub(1),
+        (ChapterChange::Previous, None) => 0,
+    };
+    chapters
+        .get(index)
+        .map(|c| c.start)
+        .ok_or_else(|| PlayerError::Queue(format!("No chapter {}", index + 1)))
+}
+
+/// Lists chapters for clients, one per line
+pub fn list(chapters: &[Chapter]) -> String {
+    chapters
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let title = c.title.as_deref().unwrap_or("");
+            format!("chapter {}: {:.2} {title}\n", i + 1, c.start)
+        })
+        .collect()
+}
@@ -0,0 +1,423 @@
+//! Chapters of MP4 files, e.g. M4B audiobooks.
+//!
+//! They are either a Nero `chpl` box in the movie's user data, or a QuickTime
+//! text track that the audio track refers to, with one sample per chapter.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{invalid, Chapter};
+
+/// Nero chapter times are in 100 ns units
+const CHPL_TIMESCALE: f64 = 10_000_000.0;
+/// More than any book has, so a broken table can't take up all the memory
+const MAX_CHAPTERS: usize = 10_000;
+
+/// A box, with where its contents are in the file
+#[derive(Debug, Clone, Copy)]
+struct Atom {
+    kind: [u8; 4],
+    start: u64,
+    end: u64,
+}
+
+/// A track, with only what's needed to find chapters
+#[derive(Default)]
+struct Track {
+    id: u32,
+    /// Tracks this one takes its chapters from
+    chapters: Vec<u32>,
+    atom: Option<Atom>,
+}
+
+pub fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Chapter>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let Some(moov) = find(reader, 0, len, b"moov")? else {
+        return Ok(vec![]);
+    };
+
+    if let Some(chpl) = path(reader, moov, &[b"udta", b"chpl"])? {
+        let chapters = read_chpl(&body(reader, chpl)?)?;
+        if !chapters.is_empty() {
+            return Ok(chapters);
+        }
+    }
+
+    let mut tracks = vec![];
+    for trak in children(reader, moov.start, moov.end)? {
+        if &trak.kind == b"trak" {
+            tracks.push(read_track(reader, trak)?);
+        }
+    }
+    let chapter_track = tracks
+        .iter()
+        .flat_map(|track| &track.chapters)
+        .find_map(|id| tracks.iter().find(|track| track.id == *id));
+    match chapter_track.and_then(|track| track.atom) {
+        Some(trak) => read_text_track(reader, trak),
+        None => Ok(vec![]),
+    }
+}
+
+fn read_chpl(chpl: &[u8]) -> io::Result<Vec<Chapter>> {
+    let mut data = Data(chpl);
+    let version = data.u8()?;
+    data.skip(3)?;
+    if version > 0 {
+        data.skip(4)?;
+    }
+
+    let count = data.u8()?;
+    let mut chapters = vec![];
+    for _ in 0..count {
+        let start = data.u64()? as f64 / CHPL_TIMESCALE;
+        let len = data.u8()? as usize;
+        let title = String::from_utf8_lossy(data.take(len)?).trim().to_string();
+        chapters.push(Chapter {
+            title: (!title.is_empty()).then_some(title),
+            start,
+        });
+    }
+    Ok(chapters)
+}
+
+fn read_track<R: Read + Seek>(reader: &mut R, trak: Atom) -> io::Result<Track> {
+    let mut track = Track {
+        atom: Some(trak),
+        ..Track::default()
+    };
+    if let Some(tkhd) = find(reader, trak.start, trak.end, b"tkhd")? {
+        let tkhd = body(reader, tkhd)?;
+        let mut data = Data(&tkhd);
+        // The times before the id are twice as long in version 1
+        let times = if data.u8()? == 1 { 16 } else { 8 };
+        data.skip(3 + times)?;
+        track.id = data.u32()?;
+    }
+    if let Some(chap) = path(reader, trak, &[b"tref", b"chap"])? {
+        let chap = body(reader, chap)?;
+        let mut data = Data(&chap);
+        while !data.0.is_empty() {
+            track.chapters.push(data.u32()?);
+        }
+    }
+    Ok(track)
+}
+
+/// Reads a text track's samples as chapters, each starting when its sample
+/// does
+fn read_text_track<R: Read + Seek>(reader: &mut R, trak: Atom) -> io::Result<Vec<Chapter>> {
+    let Some(mdhd) = path(reader, trak, &[b"mdia", b"mdhd"])? else {
+        return Ok(vec![]);
+    };
+    let Some(stbl) = path(reader, trak, &[b"mdia", b"minf", b"stbl"])? else {
+        return Ok(vec![]);
+    };
+
+    let mdhd = body(reader, mdhd)?;
+    let mut data = Data(&mdhd);
+    let times = if data.u8()? == 1 { 16 } else { 8 };
+    data.skip(3 + times)?;
+    let timescale = data.u32()?.max(1) as f64;
+
+    let table = |reader: &mut R, kind: &[u8; 4]| -> io::Result<Vec<u8>> {
+        match find(reader, stbl.start, stbl.end, kind)? {
+            Some(atom) => body(reader, atom),
+            // Version, flags and no entries, e.g. for 64-bit offsets when
+            // there are only 32-bit ones
+            None => Ok(vec![0; 8]),
+        }
+    };
+
+    // Sample times from their durations
+    let mut starts = vec![];
+    let stts = table(reader, b"stts")?;
+    let mut data = Data(stts.get(4..).unwrap_or_default());
+    let mut time = 0u64;
+    for _ in 0..data.count()? {
+        let (count, delta) = (data.u32()? as usize, data.u32()?);
+        for _ in 0..count.min(MAX_CHAPTERS - starts.len()) {
+            starts.push(time as f64 / timescale);
+            time += delta as u64;
+        }
+    }
+
+    let stsz = table(reader, b"stsz")?;
+    let mut data = Data(stsz.get(4..).unwrap_or_default());
+    // Either one size for every sample or a size for each
+    let size = data.u32()?;
+    let mut sizes = vec![];
+    if size == 0 {
+        for _ in 0..data.count()? {
+            sizes.push(data.u32()?);
+        }
+    }
+
+    let mut offsets = vec![];
+    let stco = table(reader, b"stco")?;
+    let mut data = Data(stco.get(4..).unwrap_or_default());
+    for _ in 0..data.count()? {
+        offsets.push(data.u32()? as u64);
+    }
+    let co64 = table(reader, b"co64")?;
+    let mut data = Data(co64.get(4..).unwrap_or_default());
+    for _ in 0..data.count()? {
+        offsets.push(data.u64()?);
+    }
+
+    // Which chunks hold how many samples, from the first chunk of each run on
+    let stsc = table(reader, b"stsc")?;
+    let mut data = Data(stsc.get(4..).unwrap_or_default());
+    let mut runs = vec![];
+    for _ in 0..data.count()? {
+        runs.push((data.u32()? as usize, data.u32()? as usize));
+        data.skip(4)?;
+    }
+
+    let mut chapters = vec![];
+    let mut sample = 0;
+    for (chunk, &offset) in offsets.iter().enumerate() {
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= chunk + 1)
+            .map_or(1, |(_, samples)| *samples);
+        let mut offset = offset;
+        for _ in 0..per_chunk {
+            let size = if size > 0 {
+                Some(size)
+            } else {
+                sizes.get(sample).copied()
+            };
+            let (Some(&start), Some(size)) = (starts.get(sample), size) else {
+                return Ok(chapters);
+            };
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut text = vec![0; size as usize];
+            reader.read_exact(&mut text)?;
+            chapters.push(Chapter {
+                title: text_sample(&text),
+                start,
+            });
+            offset += size as u64;
+            sample += 1;
+        }
+    }
+    Ok(chapters)
+}
+
+/// A text sample is its length followed by UTF-8, or UTF-16 with a byte order
+/// mark
+fn text_sample(sample: &[u8]) -> Option<String> {
+    let len = u16::from_be_bytes(sample.get(..2)?.try_into().unwrap()) as usize;
+    let text = sample.get(2..2 + len)?;
+    let title = match text {
+        [0xfe, 0xff, utf16 @ ..] => {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    let title = title.trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+/// The boxes between `start` and `end`
+fn children<R: Read + Seek>(reader: &mut R, start: u64, end: u64) -> io::Result<Vec<Atom>> {
+    let mut atoms = vec![];
+    let mut position = start;
+    while position + 8 <= end {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let kind = header[4..].try_into().unwrap();
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // Runs to the end of its parent
+            0 => (end - position, 8),
+            1 => {
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                (u64::from_be_bytes(size), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_len || position + size > end {
+            return Err(invalid("MP4 box runs past its parent"));
+        }
+        atoms.push(Atom {
+            kind,
+            start: position + header_len,
+            end: position + size,
+        });
+        position += size;
+    }
+    Ok(atoms)
+}
+
+fn find<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    kind: &[u8; 4],
+) -> io::Result<Option<Atom>> {
+    Ok(children(reader, start, end)?
+        .into_iter()
+        .find(|atom| &atom.kind == kind))
+}
+
+/// Follows a path of boxes down from `atom`
+fn path<R: Read + Seek>(
+    reader: &mut R,
+    atom: Atom,
+    kinds: &[&[u8; 4]],
+) -> io::Result<Option<Atom>> {
+    let mut atom = atom;
+    for kind in kinds {
+        match find(reader, atom.start, atom.end, kind)? {
+            Some(child) => atom = child,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(atom))
+}
+
+fn body<R: Read + Seek>(reader: &mut R, atom: Atom) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(atom.start))?;
+    let mut body = vec![];
+    reader.take(atom.end - atom.start).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+/// Big-endian fields read off the front of a box
+struct Data<'a>(&'a [u8]);
+
+impl<'a> Data<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("MP4 box is too short"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// An entry count, which can't be more than the entries left
+    fn count(&mut self) -> io::Result<usize> {
+        let count = self.u32()? as usize;
+        if count > self.0.len() {
+            return Err(invalid("MP4 table is too short"));
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend(kind);
+        atom.extend(body);
+        atom
+    }
+
+    fn full_atom(kind: &[u8; 4], fields: &[u32]) -> Vec<u8> {
+        let body: Vec<u8> = [0]
+            .iter()
+            .chain(fields)
+            .flat_map(|f: &u32| f.to_be_bytes())
+            .collect();
+        atom(kind, &body)
+    }
+
+    #[test]
+    fn reads_nero_chapters() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, title) in [(0u64, "Intro"), (15_000_000, "One")] {
+            chpl.extend(start.to_be_bytes());
+            chpl.push(title.len() as u8);
+            chpl.extend(title.as_bytes());
+        }
+        let mut file = atom(b"ftyp", b"M4B ");
+        file.extend(atom(b"moov", &atom(b"udta", &atom(b"chpl", &chpl))));
+
+        let chapters = read(&mut Cursor::new(file)).unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].title.as_deref(), Some("One"));
+        assert_eq!(chapters[1].start, 1.5);
+    }
+
+    #[test]
+    fn reads_quicktime_chapter_track() {
+        // Samples of 5 and 7 bytes in one chunk, at offset 8 after the ftyp
+        let mut file = atom(b"ftyp", b"M4A ");
+        let mut samples = vec![0, 3];
+        samples.extend(b"One");
+        samples.extend([0, 5, 0xfe, 0xff, 0, b'T', 0]);
+        let samples_start = file.len() as u32 + 8;
+        file.extend(atom(b"mdat", &samples));
+
+        let audio = [
+            full_atom(b"tkhd", &[0, 0, 1]),
+            atom(b"tref", &atom(b"chap", &2u32.to_be_bytes())),
+        ]
+        .concat();
+        let stbl = [
+            full_atom(b"stts", &[2, 1, 1000, 1, 500]),
+            full_atom(b"stsz", &[0, 2, 5, 7]),
+            full_atom(b"stsc", &[1, 1, 2, 1]),
+            full_atom(b"stco", &[1, samples_start]),
+        ]
+        .concat();
+        let text = [
+            full_atom(b"tkhd", &[0, 0, 2]),
+            atom(
+                b"mdia",
+                &[
+                    full_atom(b"mdhd", &[0, 0, 1000, 1500]),
+                    atom(b"minf", &atom(b"stbl", &stbl)),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let moov = [atom(b"trak", &audio), atom(b"trak", &text)].concat();
+        file.extend(atom(b"moov", &moov));
+
+        let chapters = read(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            chapters,
+            [
+                Chapter {
+                    title: Some("One".to_string()),
+                    start: 0.0
+                },
+                Chapter {
+                    title: Some("T".to_string()),
+                    start: 1.0
+                },
+            ]
+        );
+    }
+}
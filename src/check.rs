@@ -0,0 +1,103 @@
+use std::{error::Error, fs::File, io::ErrorKind, path::Path};
+
+use log::debug;
+use symphonia::{
+    core::{errors::Error as SymphoniaError, io::MediaSourceStream, probe::Hint},
+    default,
+};
+
+use crate::{
+    cue,
+    error::PlayerError,
+    replaygain,
+    song::{SongReader, SongReaderError},
+};
+
+/// Decodes every song under `path` without playing it and lists the ones that
+/// are broken, along with why
+pub fn check<T: AsRef<Path>>(path: T) -> Result<(), Box<dyn Error>> {
+    let mut files = crate::handle_input_path(path)?;
+    files.sort();
+    // Cover art, text files and the like live next to songs
+    let (files, other): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| is_audio(f));
+    for file in &other {
+        debug!("Skipping {}, which isn't audio", file.display());
+    }
+
+    let mut broken = 0;
+    for file in &files {
+        debug!("Checking {}", file.display());
+        if let Err(problem) = check_file(file) {
+            println!("{}: {problem}", file.display());
+            broken += 1;
+        }
+    }
+
+    println!("{broken} of {} files are broken", files.len());
+    if !other.is_empty() {
+        println!("Skipped {} files that aren't audio", other.len());
+    }
+    if broken > 0 {
+        return Err(format!("Found {broken} broken files").into());
+    }
+    Ok(())
+}
+
+/// Whether the file is in a format there is a reader for. Files that can't be
+/// read at all count as audio, so the check reports them.
+fn is_audio(path: &Path) -> bool {
+    if path.file_name().and_then(|n| n.to_str()) == Some(replaygain::SIDECAR_NAME) {
+        return false;
+    }
+    // Tracks of a CUE sheet are checked through their audio file
+    if cue::split(path).is_some() {
+        return true;
+    }
+    let Ok(file) = File::open(path) else {
+        return true;
+    };
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let probed =
+        default::get_probe().format(&hint, stream, &Default::default(), &Default::default());
+    !matches!(probed, Err(SymphoniaError::Unsupported(_)))
+}
+
+fn check_file(path: &Path) -> Result<(), String> {
+    let mut song = SongReader::open(path).map_err(|e| e.to_string())?;
+    let mut decode_errors = 0;
+
+    loop {
+        match song.next_chunk() {
+            Ok(_) => {}
+            Err(SongReaderError::DecodeError(_)) => decode_errors += 1,
+            Err(SongReaderError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(PlayerError::from(e).to_string()),
+        }
+    }
+
+    if decode_errors > 0 {
+        return Err(format!("{decode_errors} decode errors"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::test_song;
+
+    #[test]
+    fn skips_files_that_arent_audio() {
+        let song = test_song("check/album/01.wav", 44100, 1000);
+        let album = song.parent().unwrap();
+        std::fs::write(album.join("cover.jpg"), [0xff, 0xd8, 0xff, 0xe0, 0, 16]).unwrap();
+        std::fs::write(album.join("notes.txt"), "Recorded live").unwrap();
+        std::fs::write(album.join(replaygain::SIDECAR_NAME), "01.wav -3.2 0.9\n").unwrap();
+        assert!(check(album).is_ok());
+    }
+}
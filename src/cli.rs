@@ -0,0 +1,180 @@
+use std::error::Error;
+
+use crate::{
+    decoder::OnDecodeError,
+    dsp::{speed::SpeedMode, Stage},
+    queue::Repeat,
+    replaygain::ReplayGainMode,
+    volume::VolumeCurve,
+};
+
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 4.0;
+
+/// Options that change how the player behaves, parsed from the command line.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Resume playback when a sink that disappeared mid-song comes back
+    pub resume_on_reconnect: bool,
+    /// ReplayGain mode to start with
+    pub replay_gain: ReplayGainMode,
+    /// Seconds to crossfade between songs, 0 to disable
+    pub crossfade: f32,
+    /// Length of the fades around pause, resume, seek and skip
+    pub fade_ms: u32,
+    /// How the volume level maps onto the stream's gain
+    pub volume_curve: VolumeCurve,
+    /// Equalizer preset or profile to start with
+    pub eq: Option<String>,
+    /// Processing stages run after the equalizer
+    pub dsp: Vec<Stage>,
+    /// Playback speed to start with
+    pub speed: f32,
+    pub speed_mode: SpeedMode,
+    /// Songs at least this many seconds long resume where they were left off
+    pub resume_threshold: f64,
+    /// Continue the session saved by the last run
+    pub resume: bool,
+    pub shuffle: bool,
+    pub repeat: Repeat,
+    /// Wait for songs to be added instead of exiting when the queue runs out
+    pub daemon: bool,
+    /// Decode errors in a row that a song is given up after
+    pub max_decode_errors: u32,
+    pub on_decode_error: OnDecodeError,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            resume_on_reconnect: false,
+            replay_gain: ReplayGainMode::default(),
+            crossfade: 0.0,
+            fade_ms: 50,
+            volume_curve: VolumeCurve::default(),
+            eq: None,
+            dsp: vec![],
+            speed: 1.0,
+            speed_mode: SpeedMode::default(),
+            resume_threshold: 600.0,
+            resume: false,
+            shuffle: true,
+            repeat: Repeat::default(),
+            daemon: false,
+            max_decode_errors: 50,
+            on_decode_error: OnDecodeError::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Mode {
+    /// Play every song under a path. Only optional when resuming a session or
+    /// running as a daemon.
+    Play(Option<String>),
+    /// Measure loudness under a path and write ReplayGain sidecars
+    Scan(String),
+    /// Decode everything under a path and list the files that are broken
+    Check(String),
+    /// Read the tags of every song under a path into the library index
+    Index(String),
+    /// Run every song under the inputs through the decoder into a file
+    Render { inputs: Vec<String>, output: String },
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub mode: Mode,
+    pub options: Options,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, Box<dyn Error>> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut subcommand = None;
+        let mut paths = vec![];
+        let mut output = None;
+        let mut options = Options::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Expected a value for {arg}"));
+            match arg.as_str() {
+                "--resume-on-reconnect" => options.resume_on_reconnect = true,
+                "--replay-gain" => options.replay_gain = value()?.parse()?,
+                "--crossfade" => options.crossfade = value()?.parse()?,
+                "--fade" => options.fade_ms = value()?.parse()?,
+                "--volume-curve" => options.volume_curve = value()?.parse()?,
+                "--eq" => options.eq = Some(value()?),
+                "--dsp" => options.dsp = Stage::parse_chain(&value()?)?,
+                "--speed" => options.speed = parse_speed(&value()?)?,
+                "--speed-mode" => options.speed_mode = value()?.parse()?,
+                "--resume-threshold" => options.resume_threshold = value()?.parse()?,
+                "--resume" => options.resume = true,
+                "--no-shuffle" => options.shuffle = false,
+                "--repeat" => options.repeat = value()?.parse()?,
+                "--daemon" => options.daemon = true,
+                "--max-decode-errors" => options.max_decode_errors = value()?.parse()?,
+                "--on-decode-error" => options.on_decode_error = value()?.parse()?,
+                "-o" | "--output" => output = Some(value()?),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unrecognized option: {flag}").into())
+                }
+                "scan" | "check" | "index" | "render"
+                    if subcommand.is_none() && paths.is_empty() =>
+                {
+                    subcommand = Some(arg)
+                }
+                _ => paths.push(arg),
+            }
+        }
+
+        // Only rendering takes more than one path
+        if subcommand.as_deref() != Some("render") && paths.len() > 1 {
+            return Err(format!("Unexpected argument: {}", paths[1]).into());
+        }
+        let path = paths.first().cloned();
+
+        let mode = match subcommand.as_deref() {
+            Some("scan") => Mode::Scan(path.ok_or("Expected a path")?),
+            Some("check") => Mode::Check(path.ok_or("Expected a path")?),
+            Some("index") => Mode::Index(path.ok_or("Expected a path")?),
+            Some(_) if paths.is_empty() => return Err("Expected a path".into()),
+            Some(_) => Mode::Render {
+                inputs: paths,
+                output: output.ok_or("Expected an output file, e.g. -o out.wav")?,
+            },
+            None if options.resume || options.daemon => Mode::Play(path),
+            None => Mode::Play(Some(path.ok_or("Expected a path")?)),
+        };
+
+        Ok(Self { mode, options })
+    }
+}
+
+/// Parses a playback speed, limited to a range that still sounds like
+/// something
+pub fn parse_speed(s: &str) -> Result<f32, Box<dyn Error>> {
+    let speed: f32 = s.parse()?;
+    // NaN would get through the clamp
+    if !speed.is_finite() {
+        return Err(format!("Invalid speed: {s}").into());
+    }
+    Ok(speed.clamp(MIN_SPEED, MAX_SPEED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!(parse_speed("1.25").unwrap(), 1.25);
+        assert_eq!(parse_speed("100").unwrap(), MAX_SPEED);
+        for invalid in ["nan", "NaN", "inf", "-inf", "fast"] {
+            assert!(parse_speed(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -1,21 +1,40 @@
-use std::str::FromStr;
+use std::{
+    io,
+    os::fd::{FromRawFd, RawFd},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use async_std::{
-    io::{prelude::BufReadExt, BufReader},
+    io::{prelude::BufReadExt, BufReader, WriteExt},
     os::unix::net::{UnixListener, UnixStream},
     path::Path,
     stream::StreamExt,
     task,
 };
-use futures::{channel::mpsc, SinkExt};
+use futures::channel::oneshot;
 use log::{debug, warn};
 use symphonia::core::units::Time;
 
-use crate::pw::PipewireLoopTx;
+use crate::{
+    chapter::{self, ChapterChange},
+    cli::parse_speed,
+    dsp::{eq::EqChange, speed::SpeedMode, Stage},
+    error::PlayerError,
+    events,
+    library::{Entry, Library, Query},
+    queue::Repeat,
+    replaygain::ReplayGainMode,
+    shutdown,
+    status::SharedStatus,
+    volume::VolumeChange,
+};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
-pub type Sender<T> = mpsc::UnboundedSender<T>;
-pub type Receiver<T> = mpsc::UnboundedReceiver<T>;
+/// Commands go straight into the pipewire loop, which handles them between
+/// songs too
+pub type Sender<T> = pipewire::channel::Sender<T>;
+pub type Receiver<T> = pipewire::channel::Receiver<T>;
 
 #[derive(Clone)]
 pub enum Command {
@@ -23,14 +42,58 @@ pub enum Command {
     Play,
     Pause,
     Toggle,
-    Volume(f32),
+    Volume(VolumeChange),
     Seek(Time),
     Skip,
+    ReplayGain(ReplayGainMode),
+    Crossfade(f32),
+    Fade(u32),
+    Eq(EqChange),
+    Dsp(Vec<Stage>),
+    Speed(f32),
+    SpeedMode(SpeedMode),
+    Bookmark(String),
+    Goto(String),
+    Shuffle(bool),
+    Repeat(Repeat),
+    /// The songs `Add` found under its path, since walking a directory is
+    /// done on this thread
+    AddResolved(PathBuf, Vec<PathBuf>),
+    /// What `AddSearch` found, since the library is read on this thread
+    AddFound(Query, Vec<PathBuf>),
+    Chapter(ChapterChange),
+    Quit,
     // For this thread
-    UpdatePwSender(PipewireLoopTx),
+    Status,
+    Chapters,
+    Search(Query),
+    Add(PathBuf),
+    AddSearch(Query),
+    Subscribe,
     // For application
     Done,
-    Quit,
+}
+
+/// What a client is told once its command was handled
+pub type Reply = std::result::Result<(), PlayerError>;
+
+/// A command along with where to send the reply to, so every client gets the
+/// answers to its own commands
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Reply>,
+}
+
+impl Request {
+    pub fn new(command: Command) -> (Self, oneshot::Receiver<Reply>) {
+        let (reply, rx) = oneshot::channel();
+        (Self { command, reply }, rx)
+    }
+
+    /// For commands that don't come from a client, e.g. on a signal
+    pub fn without_reply(command: Command) -> Self {
+        Self::new(command).0
+    }
 }
 
 impl std::fmt::Debug for Command {
@@ -39,10 +102,36 @@ impl std::fmt::Debug for Command {
             Command::Play => write!(f, "Command::Play"),
             Command::Pause => write!(f, "Command::Pause"),
             Command::Toggle => write!(f, "Command::Toggle"),
-            Command::Volume(v) => write!(f, "Command::Volume({v})"),
+            Command::Volume(v) => write!(f, "Command::Volume({v:?})"),
             Command::Seek(s) => write!(f, "Command::Seek({s:?})"),
             Command::Skip => write!(f, "Command::Skip"),
-            Command::UpdatePwSender(_) => write!(f, "Command::UpdatePwSender(_)"),
+            Command::ReplayGain(m) => write!(f, "Command::ReplayGain({m})"),
+            Command::Crossfade(s) => write!(f, "Command::Crossfade({s})"),
+            Command::Fade(ms) => write!(f, "Command::Fade({ms})"),
+            Command::Eq(c) => write!(f, "Command::Eq({c:?})"),
+            Command::Dsp(s) => write!(f, "Command::Dsp({s:?})"),
+            Command::Speed(s) => write!(f, "Command::Speed({s})"),
+            Command::SpeedMode(m) => write!(f, "Command::SpeedMode({m})"),
+            Command::Bookmark(n) => write!(f, "Command::Bookmark({n})"),
+            Command::Goto(n) => write!(f, "Command::Goto({n})"),
+            Command::Shuffle(s) => write!(f, "Command::Shuffle({s})"),
+            Command::Repeat(r) => write!(f, "Command::Repeat({r})"),
+            Command::Add(p) => write!(f, "Command::Add({})", p.display()),
+            Command::AddResolved(p, songs) => write!(
+                f,
+                "Command::AddResolved({}, {} songs)",
+                p.display(),
+                songs.len()
+            ),
+            Command::AddSearch(q) => write!(f, "Command::AddSearch({q})"),
+            Command::AddFound(q, songs) => {
+                write!(f, "Command::AddFound({q}, {} songs)", songs.len())
+            }
+            Command::Chapter(c) => write!(f, "Command::Chapter({c:?})"),
+            Command::Status => write!(f, "Command::Status"),
+            Command::Chapters => write!(f, "Command::Chapters"),
+            Command::Search(q) => write!(f, "Command::Search({q})"),
+            Command::Subscribe => write!(f, "Command::Subscribe"),
             Command::Done => write!(f, "Command::Done"),
             Command::Quit => write!(f, "Command::Quit"),
         }
@@ -50,9 +139,15 @@ impl std::fmt::Debug for Command {
 }
 
 impl FromStr for Command {
-    type Err = Box<dyn std::error::Error>;
+    type Err = PlayerError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::parse(s).map_err(|e| PlayerError::Protocol(e.to_string()))
+    }
+}
+
+impl Command {
+    fn parse(s: &str) -> std::result::Result<Self, Box<dyn std::error::Error>> {
         let mut parts = s.split_whitespace();
 
         match parts.next().ok_or("Empty command")? {
@@ -62,9 +157,40 @@ impl FromStr for Command {
             "quit" => Ok(Self::Quit),
             "done" => Ok(Self::Done),
             "skip" => Ok(Self::Skip),
+            "status" => Ok(Self::Status),
+            "subscribe" => Ok(Self::Subscribe),
+            "chapters" => Ok(Self::Chapters),
+            "chapter" => {
+                let change = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::Chapter(change))
+            }
             "volume" | "vol" => {
-                let volume: f32 = parts.next().ok_or("Expected argument")?.parse()?;
-                Ok(Self::Volume(volume / 100f32))
+                let arg = parts.next().ok_or("Expected argument")?;
+                let volume: f32 = arg.parse()?;
+                // A sign makes the change relative to the current volume
+                if arg.starts_with(['+', '-']) {
+                    Ok(Self::Volume(VolumeChange::Adjust(volume / 100f32)))
+                } else {
+                    Ok(Self::Volume(VolumeChange::Set(volume / 100f32)))
+                }
+            }
+            "mute" => Ok(Self::Volume(VolumeChange::Mute(true))),
+            "unmute" => Ok(Self::Volume(VolumeChange::Mute(false))),
+            "balance" => {
+                let balance: f32 = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::Volume(VolumeChange::Balance(balance / 100f32)))
+            }
+            "channel-volume" => {
+                let channel = match parts.next().ok_or("Expected channel")? {
+                    "left" | "l" | "fl" => 0,
+                    "right" | "r" | "fr" => 1,
+                    n => n.parse()?,
+                };
+                let volume: f32 = parts.next().ok_or("Expected volume")?.parse()?;
+                Ok(Self::Volume(VolumeChange::Channel(
+                    channel,
+                    volume / 100f32,
+                )))
             }
             "seek" => {
                 // TODO: parse this better
@@ -72,43 +198,129 @@ impl FromStr for Command {
                 let time = Time::from_ss(seek.parse()?, 0).ok_or("Failed to convert to time")?;
                 Ok(Self::Seek(time))
             }
+            "replaygain" | "rg" => {
+                let mode = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::ReplayGain(mode))
+            }
+            "crossfade" => {
+                let seconds: f32 = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::Crossfade(seconds.max(0.0)))
+            }
+            "fade" => {
+                let ms = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::Fade(ms))
+            }
+            "eq" => {
+                let change = parts.collect::<Vec<_>>().join(" ").parse()?;
+                Ok(Self::Eq(change))
+            }
+            "dsp" => {
+                let stages = Stage::parse_chain(&parts.collect::<Vec<_>>().join(" "))?;
+                Ok(Self::Dsp(stages))
+            }
+            "speed" => {
+                let speed = parse_speed(parts.next().ok_or("Expected argument")?)?;
+                Ok(Self::Speed(speed))
+            }
+            "speed-mode" => {
+                let mode = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::SpeedMode(mode))
+            }
+            "bookmark" => {
+                let name = parts.next().ok_or("Expected argument")?;
+                Ok(Self::Bookmark(name.to_string()))
+            }
+            "goto" => {
+                let name = parts.next().ok_or("Expected argument")?;
+                Ok(Self::Goto(name.to_string()))
+            }
+            "shuffle" => match parts.next().ok_or("Expected argument")? {
+                "on" => Ok(Self::Shuffle(true)),
+                "off" => Ok(Self::Shuffle(false)),
+                _ => Err("Expected on or off".into()),
+            },
+            "repeat" => {
+                let repeat = parts.next().ok_or("Expected argument")?.parse()?;
+                Ok(Self::Repeat(repeat))
+            }
+            "add" => {
+                // Paths may contain spaces, so take the rest of the line
+                let path = parts.collect::<Vec<_>>().join(" ");
+                if path.is_empty() {
+                    return Err("Expected argument".into());
+                }
+                Ok(Self::Add(path.into()))
+            }
+            "search" => Ok(Self::Search(parts.collect::<Vec<_>>().join(" ").parse()?)),
+            "add-search" => Ok(Self::AddSearch(
+                parts.collect::<Vec<_>>().join(" ").parse()?,
+            )),
             _ => Err("Unrecognized command".into()),
         }
     }
 }
 
-pub fn start_command_thread() -> Sender<Command> {
-    let (tx, rx) = mpsc::unbounded();
-    let tx_clone = tx.clone();
-    std::thread::spawn(move || task::block_on(accept_clients("/tmp/pwplayer.sock", tx_clone)));
-    std::thread::spawn(move || task::block_on(handle_messages(rx)));
-    tx
+pub const SOCKET_PATH: &str = "/tmp/pwplayer.sock";
+/// The first file descriptor passed by socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The channel commands go through. The player needs the receiver before the
+/// command thread starts.
+pub fn channel() -> (Sender<Request>, Receiver<Request>) {
+    pipewire::channel::channel()
 }
 
-async fn handle_messages(mut message_rx: Receiver<Command>) -> Result<()> {
-    let mut channel = None;
-    while let Some(msg) = message_rx.next().await {
-        match msg {
-            Command::UpdatePwSender(s) => channel = Some(s),
-            _ => {
-                let _ = channel.as_ref().map(|c| c.send(msg));
-            }
-        }
+pub fn start_command_thread(tx: Sender<Request>, status: SharedStatus) {
+    std::thread::spawn(move || task::block_on(accept_clients(SOCKET_PATH, tx, status)));
+}
+
+/// The listening socket systemd passes in when the player was started by
+/// socket activation, see sd_listen_fds(3)
+fn activation_listener() -> Option<UnixListener> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds == 0 {
+        return None;
     }
-    Ok(())
+    // So nothing started from here takes the socket as its own too
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    // Safety: systemd hands over the first socket as this fd and nothing else
+    // uses it
+    Some(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
 }
 
-async fn accept_clients(path: impl AsRef<Path>, message_tx: Sender<Command>) -> Result<()> {
-    let _ = async_std::fs::remove_file(&path).await;
-    let listener = UnixListener::bind(&path).await?;
+async fn accept_clients(
+    path: impl AsRef<Path>,
+    message_tx: Sender<Request>,
+    status: SharedStatus,
+) -> Result<()> {
+    let listener = match activation_listener() {
+        // systemd owns the socket, so it stays around for the next start
+        Some(listener) => {
+            debug!("Using the socket passed by systemd");
+            listener
+        }
+        None => {
+            let _ = async_std::fs::remove_file(&path).await;
+            let listener = UnixListener::bind(&path).await?;
+            let socket = path.as_ref().to_path_buf();
+            shutdown::on_shutdown(move || {
+                let _ = std::fs::remove_file(socket);
+            });
+            listener
+        }
+    };
     let mut incoming = listener.incoming();
 
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
         debug!("New client connected");
         let tx_clone = message_tx.clone();
+        let status = status.clone();
         task::spawn(async move {
-            if let Err(e) = handle_client(stream, tx_clone).await {
+            if let Err(e) = handle_client(stream, tx_clone, status).await {
                 warn!("Client error: {e:?}");
             }
         });
@@ -117,29 +329,120 @@ async fn accept_clients(path: impl AsRef<Path>, message_tx: Sender<Command>) ->
     Ok(())
 }
 
-async fn handle_client(stream: UnixStream, mut message_tx: Sender<Command>) -> Result<()> {
+async fn handle_client(
+    stream: UnixStream,
+    message_tx: Sender<Request>,
+    status: SharedStatus,
+) -> Result<()> {
     let reader = BufReader::new(&stream);
     let mut lines = reader.lines();
 
     while let Some(line) = lines.next().await {
         let line = line?;
-        let c: Command = match line.parse() {
+        let c = match line.parse::<Command>() {
             Ok(c) => {
                 debug!("Recieved command: {c:?}");
                 c
             }
             Err(e) => {
-                warn!("Bad command from client: {e}");
+                debug!("Bad command from client: {e}");
+                (&stream)
+                    .write_all(format!("error: {e}\n").as_bytes())
+                    .await?;
                 continue;
             }
         };
 
-        match c {
+        let reply = match c {
             Command::Done => return Ok(()),
-            Command::Quit => std::process::exit(0),
-            Command::Seek(_c) => warn!("Seek not implemented"),
-            _ => message_tx.send(c).await.unwrap(),
-        }
+            Command::Status => {
+                (&stream).write_all(status.report().as_bytes()).await?;
+                continue;
+            }
+            Command::Chapters => {
+                let chapters = status.chapters();
+                if !chapters.is_empty() {
+                    (&stream)
+                        .write_all(chapter::list(&chapters).as_bytes())
+                        .await?;
+                    continue;
+                }
+                Ok(Err(PlayerError::Queue(
+                    "The song has no chapters".to_string(),
+                )))
+            }
+            Command::Search(query) => {
+                let found = search(&query).await;
+                if !found.is_empty() {
+                    let list: String = found.iter().map(|e| format!("{e}\n")).collect();
+                    (&stream).write_all(list.as_bytes()).await?;
+                    continue;
+                }
+                Ok(Err(PlayerError::Queue(format!("No songs match {query}"))))
+            }
+            Command::Subscribe => {
+                let mut events = events::subscribe();
+                let stream = stream.clone();
+                task::spawn(async move {
+                    while let Some(event) = events.next().await {
+                        if (&stream).write_all(event.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                Ok(Ok(()))
+            }
+            Command::Add(path) => match resolve(&path).await {
+                Ok(songs) => send(&message_tx, Command::AddResolved(path, songs)).await,
+                Err(e) => {
+                    let message = format!("Failed to add {}: {e}", path.display());
+                    Ok(Err(PlayerError::Io(io::Error::new(e.kind(), message))))
+                }
+            },
+            Command::AddSearch(query) => {
+                let found = search(&query).await;
+                let songs = found.into_iter().map(|e| e.path).collect();
+                send(&message_tx, Command::AddFound(query, songs)).await
+            }
+            c => send(&message_tx, c).await,
+        };
+
+        let response = match reply {
+            Ok(Ok(())) => "ok\n".to_string(),
+            Ok(Err(e)) => format!("error: {e}\n"),
+            Err(oneshot::Canceled) => "error: The player is shutting down\n".to_string(),
+        };
+        (&stream).write_all(response.as_bytes()).await?;
     }
     Ok(())
 }
+
+/// Hands a command to the player and waits for its reply
+async fn send(
+    message_tx: &Sender<Request>,
+    command: Command,
+) -> std::result::Result<Reply, oneshot::Canceled> {
+    // A request that can't be delivered is dropped along with its reply
+    // sender, which cancels the reply
+    let (request, reply) = Request::new(command);
+    let _ = message_tx.send(request);
+    reply.await
+}
+
+/// Finds the songs under a path, on a thread of their own since that may walk
+/// a whole directory tree
+async fn resolve(path: &std::path::Path) -> io::Result<Vec<PathBuf>> {
+    let path = path.to_owned();
+    task::spawn_blocking(move || crate::handle_input_path(path)).await
+}
+
+/// Looks songs up in the library, on a thread of their own since that may
+/// read the index from disk
+async fn search(query: &Query) -> Vec<Entry> {
+    let query = query.clone();
+    task::spawn_blocking(move || {
+        let library = Library::shared();
+        library.search(&query).into_iter().cloned().collect()
+    })
+    .await
+}
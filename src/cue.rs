@@ -0,0 +1,330 @@
+//! CUE sheets, which split one file, usually a whole album, into tracks.
+//!
+//! Every track goes into the queue as the sheet's path with `#` and the track
+//! number on the end, e.g. `album.cue#03`, and plays as a song of its own.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// CUE times count frames of a CD, 75 to the second
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CueTrack {
+    pub number: u32,
+    pub file: PathBuf,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Seconds into `file` the track starts at
+    pub start: f64,
+    /// Seconds into `file` the next track starts at, if it is in the same file
+    pub end: Option<f64>,
+}
+
+pub fn is_cue(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// The queue entry for a track of a sheet
+pub fn track_path(sheet: &Path, number: u32) -> PathBuf {
+    let mut path = sheet.as_os_str().to_owned();
+    // Padded so the tracks sort in order, a sheet has at most 99
+    path.push(format!("#{number:02}"));
+    path.into()
+}
+
+/// Splits a queue entry for a track into the sheet and the track number
+pub fn split(path: &Path) -> Option<(PathBuf, u32)> {
+    let (sheet, number) = path.to_str()?.rsplit_once('#')?;
+    let number = number.parse().ok()?;
+    let sheet = PathBuf::from(sheet);
+    is_cue(&sheet).then_some((sheet, number))
+}
+
+impl CueSheet {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        // Older rippers write Latin-1, which maps byte for byte onto Unicode
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&text, dir).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })
+    }
+
+    /// Parses a sheet whose files are relative to `dir`
+    fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut sheet = Self::default();
+        let mut file = None;
+        // The track being read, whether it holds audio and whether it has a
+        // start yet
+        let mut track: Option<(CueTrack, bool, bool)> = None;
+
+        for (i, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let error = |what: &str| format!("line {}: {what}", i + 1);
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    // The name is followed by the type, e.g. WAVE
+                    let name = if rest.starts_with('"') {
+                        unquote(rest)
+                    } else {
+                        rest.rsplit_once(' ').map_or(rest, |(name, _)| name)
+                    };
+                    file = Some(dir.join(name));
+                }
+                "TRACK" => {
+                    sheet.push(track.take())?;
+                    let mut parts = rest.split_whitespace();
+                    let number = parts
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| error("Expected a track number"))?;
+                    let audio = parts
+                        .next()
+                        .is_some_and(|t| t.eq_ignore_ascii_case("AUDIO"));
+                    let file = file.clone().ok_or_else(|| error("TRACK before FILE"))?;
+                    track = Some((
+                        CueTrack {
+                            number,
+                            file,
+                            title: None,
+                            performer: None,
+                            start: 0.0,
+                            end: None,
+                        },
+                        audio,
+                        false,
+                    ));
+                }
+                "INDEX" => {
+                    let mut parts = rest.split_whitespace();
+                    let index: u32 = parts
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| error("Expected an index number"))?;
+                    let time = parts
+                        .next()
+                        .and_then(parse_time)
+                        .ok_or_else(|| error("Expected a time as mm:ss:ff"))?;
+                    // Index 0 is the gap before a track, which stays with the
+                    // one before it
+                    if index == 1 {
+                        let (track, _, started) =
+                            track.as_mut().ok_or_else(|| error("INDEX before TRACK"))?;
+                        track.start = time;
+                        *started = true;
+                    }
+                }
+                "TITLE" | "PERFORMER" => {
+                    let value = Some(unquote(rest).to_string());
+                    let is_title = command.eq_ignore_ascii_case("TITLE");
+                    match (track.as_mut(), is_title) {
+                        (Some((track, ..)), true) => track.title = value,
+                        (Some((track, ..)), false) => track.performer = value,
+                        (None, true) => sheet.title = value,
+                        (None, false) => sheet.performer = value,
+                    }
+                }
+                // REM, FLAGS, ISRC, CATALOG, PREGAP and the like
+                _ => {}
+            }
+        }
+        sheet.push(track.take())?;
+
+        // A track ends where the next one in the same file starts
+        for i in 1..sheet.tracks.len() {
+            let (before, after) = sheet.tracks.split_at_mut(i);
+            let (last, next) = (&mut before[i - 1], &after[0]);
+            if last.file == next.file {
+                last.end = Some(next.start);
+            }
+        }
+
+        if sheet.tracks.is_empty() {
+            return Err("No audio tracks".to_string());
+        }
+        Ok(sheet)
+    }
+
+    fn push(&mut self, track: Option<(CueTrack, bool, bool)>) -> Result<(), String> {
+        let Some((track, audio, started)) = track else {
+            return Ok(());
+        };
+        if !started {
+            return Err(format!("Track {} has no INDEX 01", track.number));
+        }
+        if audio {
+            self.tracks.push(track);
+        }
+        Ok(())
+    }
+
+    pub fn track(&self, number: u32) -> Option<&CueTrack> {
+        self.tracks.iter().find(|t| t.number == number)
+    }
+
+    /// The files the tracks are in
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.tracks.iter().map(|t| t.file.as_path())
+    }
+
+    /// Queue entries for every track, in order
+    pub fn entries(&self, path: &Path) -> Vec<PathBuf> {
+        self.tracks
+            .iter()
+            .map(|t| track_path(path, t.number))
+            .collect()
+    }
+}
+
+/// Takes what is between the quotes, ignoring anything after them
+fn unquote(s: &str) -> &str {
+    match s.strip_prefix('"') {
+        Some(rest) => rest.split('"').next().unwrap_or(rest),
+        None => s,
+    }
+}
+
+/// Parses `mm:ss:ff` into seconds
+fn parse_time(s: &str) -> Option<f64> {
+    let mut parts = s.split(':').map(|p| p.parse::<u32>().ok());
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+    let frames = parts.next()??;
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE Rock
+PERFORMER "The Band"
+TITLE "Live Album"
+FILE "Side A.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Opener"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second"
+    PERFORMER "Guest"
+    INDEX 00 03:58:00
+    INDEX 01 04:00:37
+FILE side-b.flac WAVE
+  TRACK 03 DATA
+    INDEX 01 00:00:00
+  TRACK 04 AUDIO
+    TITLE Unquoted
+    INDEX 01 00:02:00
+  TRACK 05 AUDIO
+    INDEX 01 10:00:74
+"#;
+
+    #[test]
+    fn parses_sheets() {
+        let sheet = CueSheet::parse(SHEET, Path::new("/music")).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Live Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+
+        let numbers: Vec<u32> = sheet.tracks.iter().map(|t| t.number).collect();
+        assert_eq!(numbers, [1, 2, 4, 5]);
+
+        let track = sheet.track(2).unwrap();
+        assert_eq!(track.file, Path::new("/music/Side A.flac"));
+        assert_eq!(track.title.as_deref(), Some("Second"));
+        assert_eq!(track.performer.as_deref(), Some("Guest"));
+        assert_eq!(
+            sheet.track(4).unwrap().file,
+            Path::new("/music/side-b.flac")
+        );
+        assert_eq!(sheet.track(4).unwrap().title.as_deref(), Some("Unquoted"));
+        assert_eq!(sheet.track(1).unwrap().performer, None);
+        assert!(sheet.track(3).is_none());
+    }
+
+    #[test]
+    fn tracks_end_where_the_next_starts() {
+        let sheet = CueSheet::parse(SHEET, Path::new("/music")).unwrap();
+        let bounds = |number| {
+            let track = sheet.track(number).unwrap();
+            (track.start, track.end)
+        };
+        // The gap before track 2 plays at the end of track 1
+        assert_eq!(bounds(1), (0.0, Some(240.0 + 37.0 / 75.0)));
+        // The last track of a file plays to its end
+        assert_eq!(bounds(2), (240.0 + 37.0 / 75.0, None));
+        assert_eq!(bounds(4), (2.0, Some(600.0 + 74.0 / 75.0)));
+        assert_eq!(bounds(5), (600.0 + 74.0 / 75.0, None));
+    }
+
+    #[test]
+    fn rejects_broken_sheets() {
+        let dir = Path::new("");
+        assert!(CueSheet::parse("TRACK 01 AUDIO\nINDEX 01 00:00:00", dir).is_err());
+        assert!(CueSheet::parse("FILE a.wav WAVE\nTRACK 01 AUDIO", dir).is_err());
+        assert!(CueSheet::parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 1:2", dir).is_err());
+        assert!(CueSheet::parse("FILE a.wav WAVE\nTRACK 01 DATA\nINDEX 01 0:0:0", dir).is_err());
+        assert!(CueSheet::parse("REM nothing", dir).is_err());
+    }
+
+    #[test]
+    fn reads_bom_and_latin_1() {
+        let dir = std::env::temp_dir().join(format!("pwplayer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let utf8 = dir.join("utf8.cue");
+        let text = "\u{feff}FILE \"Café.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n";
+        std::fs::write(&utf8, text).unwrap();
+        let sheet = CueSheet::read(&utf8).unwrap();
+        assert_eq!(sheet.tracks[0].file, dir.join("Café.flac"));
+
+        let latin1 = dir.join("latin1.cue");
+        let mut bytes = b"TITLE \"Caf\xe9\"\nFILE a.flac WAVE\n".to_vec();
+        bytes.extend(b"TRACK 01 AUDIO\nINDEX 01 00:00:00\n");
+        std::fs::write(&latin1, bytes).unwrap();
+        let sheet = CueSheet::read(&latin1).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Café"));
+    }
+
+    #[test]
+    fn track_paths_round_trip() {
+        let sheet = Path::new("/music/album.CUE");
+        let path = track_path(sheet, 3);
+        assert_eq!(path, Path::new("/music/album.CUE#03"));
+        assert_eq!(split(&path), Some((sheet.to_owned(), 3)));
+
+        let entries = CueSheet::parse(SHEET, Path::new("/music"))
+            .unwrap()
+            .entries(Path::new("/music/live.cue"));
+        let numbers: Vec<_> = entries
+            .iter()
+            .filter_map(|e| split(e))
+            .map(|(_, n)| n)
+            .collect();
+        assert_eq!(numbers, [1, 2, 4, 5]);
+
+        assert_eq!(split(Path::new("/music/album.cue")), None);
+        assert_eq!(split(Path::new("/music/take#2.flac")), None);
+        assert_eq!(split(Path::new("/music/album.cue#two")), None);
+    }
+}
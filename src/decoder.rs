@@ -0,0 +1,688 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::FRAC_PI_2,
+    fmt::Display,
+    io::ErrorKind,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use symphonia::core::units::Time;
+
+use crate::{
+    cli::Options,
+    dsp::{
+        eq::{EqSettings, Equalizer},
+        speed::{Speed, SpeedMode},
+        Chain, Processor, Stage,
+    },
+    error::PlayerError,
+    events, http,
+    queue::SharedQueue,
+    replaygain::{self, ReplayGainMode},
+    ring::{Consumer, Producer},
+    song::{SongReader, SongReaderError},
+    status::SharedStatus,
+};
+
+/// How long the decoder sleeps when the ring is full or it has nothing to do
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait for the decoder to fill the ring before starting playback
+const PREFILL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What to do with a song that can't be decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDecodeError {
+    /// Move on to the next song
+    #[default]
+    Skip,
+    /// Stop playing the queue until told to go on
+    Stop,
+}
+
+impl FromStr for OnDecodeError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "stop" => Ok(Self::Stop),
+            _ => Err(format!("Unknown decode error action: {s}")),
+        }
+    }
+}
+
+impl Display for OnDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Skip => write!(f, "skip"),
+            Self::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+/// Settings that carry over from song to song
+#[derive(Debug, Clone)]
+pub struct DecoderSettings {
+    pub replay_gain: ReplayGainMode,
+    /// Seconds to crossfade into the next song, 0 to disable
+    pub crossfade: f32,
+    pub eq: EqSettings,
+    /// Stages run after the equalizer, in order
+    pub dsp: Vec<Stage>,
+    pub speed: f32,
+    pub speed_mode: SpeedMode,
+    /// Decode errors in a row that a song is given up after
+    pub max_decode_errors: u32,
+    pub on_decode_error: OnDecodeError,
+}
+
+impl DecoderSettings {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            replay_gain: options.replay_gain,
+            crossfade: options.crossfade,
+            eq: EqSettings::startup(options.eq.as_deref()),
+            dsp: options.dsp.clone(),
+            speed: options.speed,
+            speed_mode: options.speed_mode,
+            max_decode_errors: options.max_decode_errors,
+            on_decode_error: options.on_decode_error,
+        }
+    }
+}
+
+enum DecoderCommand {
+    Seek(Time),
+    ReplayGain(ReplayGainMode),
+    Crossfade(f32),
+    Eq(EqSettings),
+    Dsp(Vec<Stage>),
+    Speed(f32, SpeedMode),
+}
+
+/// A song that was already started by a crossfade and should keep playing
+/// from where the crossfade left it.
+pub struct Preloaded {
+    pub path: PathBuf,
+    pub song: SongReader,
+}
+
+pub type Handoff = Arc<Mutex<Option<Preloaded>>>;
+
+/// Where the decoder finds the song to crossfade into, and where it leaves it
+#[derive(Clone)]
+pub struct NextSong {
+    pub queue: SharedQueue,
+    pub handoff: Handoff,
+}
+
+/// Decodes a song on its own thread and feeds the samples into a ring buffer,
+/// so the realtime thread never has to touch the disk or the decoder.
+pub struct DecoderWorker {
+    commands: mpsc::Sender<DecoderCommand>,
+    stop: Arc<AtomicBool>,
+    /// Closes the song's stream, so a decoder waiting on it can be stopped
+    closer: Option<http::Closer>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DecoderWorker {
+    pub fn spawn(
+        path: PathBuf,
+        song: SongReader,
+        producer: Producer,
+        status: SharedStatus,
+        settings: DecoderSettings,
+        next: NextSong,
+    ) -> Self {
+        let (commands, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let closer = song.closer();
+
+        let thread = thread::Builder::new()
+            .name("decoder".into())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    let mut worker = Worker::new(path, song, producer, status, settings, next);
+                    worker.run(rx, &stop)
+                }
+            })
+            .expect("Failed to spawn decoder thread");
+
+        Self {
+            commands,
+            stop,
+            closer,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn seek(&self, time: Time) {
+        self.send(DecoderCommand::Seek(time));
+    }
+
+    pub fn set_replay_gain(&self, mode: ReplayGainMode) {
+        self.send(DecoderCommand::ReplayGain(mode));
+    }
+
+    pub fn set_crossfade(&self, seconds: f32) {
+        self.send(DecoderCommand::Crossfade(seconds));
+    }
+
+    pub fn set_eq(&self, eq: EqSettings) {
+        self.send(DecoderCommand::Eq(eq));
+    }
+
+    pub fn set_dsp(&self, stages: Vec<Stage>) {
+        self.send(DecoderCommand::Dsp(stages));
+    }
+
+    pub fn set_speed(&self, speed: f32, mode: SpeedMode) {
+        self.send(DecoderCommand::Speed(speed, mode));
+    }
+
+    fn send(&self, command: DecoderCommand) {
+        if self.commands.send(command).is_ok() {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(thread) = self.thread.as_ref() {
+            thread.thread().unpark();
+        }
+    }
+}
+
+impl Drop for DecoderWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(closer) = &self.closer {
+            closer.close();
+        }
+        self.wake();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Blocks until the ring holds at least `samples` samples, the decoder is done
+/// or a timeout expires.
+pub fn prefill(consumer: &Consumer, samples: usize) {
+    let deadline = Instant::now() + PREFILL_TIMEOUT;
+    while consumer.len() < samples && !consumer.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// The next song, mixed into the tail of the current one with equal-power
+/// curves.
+struct Crossfade {
+    path: PathBuf,
+    song: SongReader,
+    gain: f32,
+    /// Samples decoded from the next song that haven't been mixed in yet
+    buffer: Vec<f32>,
+    offset: usize,
+    /// Length of the fade in frames
+    length: u64,
+    /// Frames mixed so far
+    position: u64,
+}
+
+impl Crossfade {
+    fn next_frame(&mut self, channels: usize) -> Option<&[f32]> {
+        while self.offset + channels > self.buffer.len() {
+            match self.song.next_chunk() {
+                Ok(chunk) => {
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(chunk);
+                    replaygain::apply(&mut self.buffer, self.gain);
+                    self.offset = 0;
+                }
+                Err(SongReaderError::DecodeError(e)) => warn!("Decoding error (not fatal): {e:?}"),
+                Err(_) => return None,
+            }
+        }
+
+        let frame = &self.buffer[self.offset..self.offset + channels];
+        self.offset += channels;
+        Some(frame)
+    }
+
+    fn mix(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_exact_mut(channels) {
+            let t = (self.position as f32 / self.length as f32).min(1.0);
+            let (fade_out, fade_in) = ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin());
+            self.position += 1;
+
+            let next = self.next_frame(channels);
+            for (i, sample) in frame.iter_mut().enumerate() {
+                *sample = *sample * fade_out + next.map_or(0.0, |n| n[i]) * fade_in;
+            }
+        }
+    }
+}
+
+struct Worker {
+    path: PathBuf,
+    song: SongReader,
+    producer: Producer,
+    status: SharedStatus,
+    settings: DecoderSettings,
+    next: NextSong,
+    gain: f32,
+    speed: Speed,
+    eq: Equalizer,
+    chain: Chain,
+    pending: Vec<f32>,
+    /// Where the speed stage writes before it is swapped into `pending`
+    scratch: Vec<f32>,
+    offset: usize,
+    /// Nothing is left to decode once `pending` is pushed
+    eof: bool,
+    crossfade: Option<Crossfade>,
+    /// Whether we already decided if this song crossfades into the next
+    crossfade_checked: bool,
+    /// Decode errors since the last chunk that decoded fine
+    decode_errors: u32,
+    /// Samples that went into the speed stage and haven't played yet, so a
+    /// speed change can redo them instead of seeking
+    history: VecDeque<f32>,
+    /// Frame of the song just past the end of `history`
+    history_end: u64,
+}
+
+impl Worker {
+    fn new(
+        path: PathBuf,
+        song: SongReader,
+        producer: Producer,
+        status: SharedStatus,
+        settings: DecoderSettings,
+        next: NextSong,
+    ) -> Self {
+        let gain = song.replay_gain.factor(settings.replay_gain);
+        debug!("Replay gain ({}): {gain}", settings.replay_gain);
+        let channels = song.channels as usize;
+        let eq = Equalizer::new(settings.eq.clone(), song.rate, channels);
+        let chain = Chain::new(&settings.dsp, song.rate, channels);
+        let speed = Speed::new(settings.speed, settings.speed_mode, song.rate, channels);
+        // Songs can start part way in, e.g. when resumed
+        let song_position = song.position();
+
+        Self {
+            path,
+            song,
+            producer,
+            status,
+            settings,
+            next,
+            gain,
+            speed,
+            eq,
+            chain,
+            pending: vec![],
+            scratch: vec![],
+            offset: 0,
+            eof: false,
+            crossfade: None,
+            crossfade_checked: false,
+            decode_errors: 0,
+            history: VecDeque::new(),
+            history_end: song_position,
+        }
+    }
+
+    fn run(&mut self, commands: mpsc::Receiver<DecoderCommand>, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            while let Ok(command) = commands.try_recv() {
+                self.handle(command);
+            }
+
+            if self.offset == self.pending.len() {
+                if self.eof {
+                    self.producer.set_finished(true);
+                    thread::park_timeout(POLL_INTERVAL);
+                    continue;
+                }
+                self.decode_next();
+            }
+
+            self.offset += self.producer.push(&self.pending[self.offset..]);
+            if self.offset < self.pending.len() {
+                thread::park_timeout(POLL_INTERVAL);
+            }
+        }
+    }
+
+    fn handle(&mut self, command: DecoderCommand) {
+        match command {
+            DecoderCommand::Seek(time) => self.seek(time),
+            DecoderCommand::ReplayGain(mode) => {
+                self.settings.replay_gain = mode;
+                self.gain = self.song.replay_gain.factor(mode);
+                debug!("Replay gain ({mode}): {}", self.gain);
+            }
+            DecoderCommand::Crossfade(seconds) => {
+                self.settings.crossfade = seconds;
+                self.crossfade_checked = false;
+            }
+            DecoderCommand::Eq(eq) => {
+                self.eq.set(eq.clone());
+                self.settings.eq = eq;
+            }
+            DecoderCommand::Dsp(stages) => {
+                self.chain = Chain::new(&stages, self.song.rate, self.song.channels as usize);
+                self.settings.dsp = stages;
+            }
+            DecoderCommand::Speed(speed, mode) => {
+                self.settings.speed = speed;
+                self.settings.speed_mode = mode;
+                self.speed = Speed::new(speed, mode, self.song.rate, self.song.channels as usize);
+                self.redo_unplayed();
+            }
+        }
+    }
+
+    fn seek(&mut self, time: Time) {
+        match self.song.seek_time(time) {
+            Ok(actual) => {
+                debug!("Seeked to {actual:?}");
+                self.status
+                    .set_seek_target(self.song.position(), self.settings.speed);
+                self.history.clear();
+                self.history_end = self.song.position();
+                self.pending.clear();
+                self.offset = 0;
+                self.eof = false;
+                self.crossfade = None;
+                self.crossfade_checked = false;
+                self.speed.reset();
+                self.eq.reset();
+                self.chain.reset();
+                self.producer.set_finished(false);
+                self.producer.clear();
+            }
+            Err(e) => warn!("Failed to seek: {e:?}"),
+        }
+    }
+
+    fn decode_next(&mut self) {
+        self.start_crossfade();
+
+        match self.song.next_chunk() {
+            Ok(chunk) => {
+                self.decode_errors = 0;
+                self.pending.clear();
+                self.pending.extend_from_slice(chunk);
+                replaygain::apply(&mut self.pending, self.gain);
+                self.offset = 0;
+
+                if let Some(crossfade) = self.crossfade.as_mut() {
+                    crossfade.mix(&mut self.pending, self.song.channels as usize);
+                }
+                self.remember_pending();
+                self.process_pending(false);
+
+                if let Some(title) = self.song.title_change() {
+                    self.status.set_title(title);
+                }
+            }
+            Err(SongReaderError::DecodeError(e)) => {
+                warn!("Decoding error (not fatal): {e:?}");
+                self.decode_errors += 1;
+                if self.decode_errors > self.settings.max_decode_errors {
+                    let e = PlayerError::Decode(SongReaderError::DecodeError(e));
+                    self.give_up(e);
+                }
+            }
+            Err(SongReaderError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                debug!("Song finished decoding");
+                self.end_of_song();
+            }
+            Err(e) => self.give_up(e.into()),
+        }
+    }
+
+    /// The frame of the song playing right now
+    fn played_frame(&self) -> u64 {
+        (self.status.position() * self.song.rate as f64).round() as u64
+    }
+
+    /// Where `history` starts, in frames of the song
+    fn history_start(&self) -> u64 {
+        self.history_end - (self.history.len() / self.song.channels as usize) as u64
+    }
+
+    /// Adds the freshly decoded samples to `history` and forgets what has
+    /// been played since
+    fn remember_pending(&mut self) {
+        let channels = self.song.channels as usize;
+        // The position is stale until the realtime thread applies a clear
+        if !self.producer.is_clearing() {
+            let played = self.played_frame().saturating_sub(self.history_start()) as usize;
+            let played = (played * channels).min(self.history.len());
+            self.history.drain(..played);
+        }
+        self.history.extend(&self.pending);
+        self.history_end += (self.pending.len() / channels) as u64;
+    }
+
+    /// Drops what was buffered at the old speed and runs what hasn't been
+    /// played yet through the new speed stage, from right where playback is
+    fn redo_unplayed(&mut self) {
+        let played = self.played_frame();
+        let unplayed = (self.history_start()..=self.history_end).contains(&played);
+        self.status.set_seek_target(played, self.settings.speed);
+
+        // The tail of a crossfade isn't kept, so it plays out at the old speed
+        if self.eof || self.producer.is_clearing() || !unplayed {
+            self.status.finish_seek();
+            return;
+        }
+
+        let channels = self.song.channels as usize;
+        self.history
+            .drain(..(played - self.history_start()) as usize * channels);
+        self.pending.clear();
+        self.pending.extend(&self.history);
+        self.offset = 0;
+        self.producer.clear();
+        self.process_pending(false);
+    }
+
+    /// Ends a song that can't be decoded any further
+    fn give_up(&mut self, e: PlayerError) {
+        events::song_error(&self.path, &e);
+        if self.settings.on_decode_error == OnDecodeError::Stop {
+            self.status.stop_queue();
+        }
+        self.end_of_song();
+    }
+
+    /// Runs the freshly decoded samples in `pending` through the speed stage,
+    /// the equalizer and the chain. `last` flushes the speed stage.
+    fn process_pending(&mut self, last: bool) {
+        if !self.speed.is_bypassed() {
+            self.scratch.clear();
+            self.speed.process(&self.pending, &mut self.scratch);
+            if last {
+                self.speed.flush(&mut self.scratch);
+            }
+            std::mem::swap(&mut self.pending, &mut self.scratch);
+        }
+
+        self.eq.process(&mut self.pending);
+        self.chain.process(&mut self.pending);
+    }
+
+    fn end_of_song(&mut self) {
+        self.eof = true;
+        self.pending.clear();
+        self.offset = 0;
+
+        if let Some(crossfade) = self.crossfade.take() {
+            // Whatever the next song decoded past the end of this one still
+            // has to be played on this stream
+            self.pending
+                .extend_from_slice(&crossfade.buffer[crossfade.offset..]);
+
+            *self.next.handoff.lock().unwrap() = Some(Preloaded {
+                path: crossfade.path,
+                song: crossfade.song,
+            });
+        }
+        self.process_pending(true);
+    }
+
+    /// Opens the next song once the current one is within the crossfade
+    /// duration of its end.
+    fn start_crossfade(&mut self) {
+        if self.crossfade_checked || self.crossfade.is_some() || self.settings.crossfade <= 0.0 {
+            return;
+        }
+
+        let Some(length) = self.song.length else {
+            debug!("Song length is unknown, not crossfading");
+            self.crossfade_checked = true;
+            return;
+        };
+
+        // The fade is timed in played seconds, which cover more of the song
+        // when it is sped up
+        let fade_frames =
+            (self.settings.crossfade * self.settings.speed * self.song.rate as f32) as u64;
+        let remaining = length.saturating_sub(self.song.position());
+        if remaining > fade_frames {
+            return;
+        }
+        self.crossfade_checked = true;
+
+        let Some(path) = self.next.queue.lock().unwrap().peek().cloned() else {
+            return;
+        };
+        let next = match SongReader::open(&path) {
+            Ok(next) => next,
+            Err(e) => {
+                warn!("Failed to open {} for crossfade: {e:?}", path.display());
+                return;
+            }
+        };
+
+        if next.rate != self.song.rate || next.channels != self.song.channels {
+            debug!("Next song has a different format, not crossfading");
+            return;
+        }
+
+        let gapless = self.song.album.is_some()
+            && self.song.album == next.album
+            && self.song.track.is_some()
+            && next.track == self.song.track.map(|t| t + 1);
+        if gapless {
+            debug!("Next song continues the album, not crossfading");
+            return;
+        }
+
+        info!("Crossfading into {}", path.display());
+        let gain = next.replay_gain.factor(self.settings.replay_gain);
+        self.crossfade = Some(Crossfade {
+            path,
+            song: next,
+            gain,
+            buffer: vec![],
+            offset: 0,
+            length: remaining.max(1),
+            position: 0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{queue::Queue, ring, song, status::Status};
+
+    const RATE: u32 = 8000;
+
+    /// Decodes a test song, changing the speed once `change_at` frames have
+    /// played. Returns the output before and after the change.
+    fn play(change_at: usize, speed: f32) -> (Vec<f32>, Vec<f32>) {
+        let path = song::test_song(
+            &format!("speed-{change_at}-{speed}.wav"),
+            RATE,
+            4 * RATE as usize,
+        );
+        let song = SongReader::open(&path).unwrap();
+        let status = Arc::new(Status::default());
+        status.start_song(None, RATE, 0, 1.0);
+        let settings = DecoderSettings {
+            replay_gain: ReplayGainMode::Off,
+            crossfade: 0.0,
+            eq: EqSettings::default(),
+            dsp: vec![],
+            speed: 1.0,
+            speed_mode: SpeedMode::Stretch,
+            max_decode_errors: 0,
+            on_decode_error: OnDecodeError::Skip,
+        };
+        let next = NextSong {
+            queue: Arc::new(Mutex::new(Queue::new(vec![]))),
+            handoff: Handoff::default(),
+        };
+        let (producer, mut consumer) = ring::ring(RATE as usize * 2);
+        let worker = DecoderWorker::spawn(path, song, producer, status.clone(), settings, next);
+
+        let mut before = vec![0.0; change_at * 2];
+        let mut read = 0;
+        while read < before.len() {
+            read += consumer.pop(&mut before[read..]);
+        }
+        status.advance(change_at as u64);
+
+        worker.set_speed(speed, SpeedMode::Stretch);
+        while !consumer.take_clear() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        status.finish_seek();
+
+        let mut after = vec![];
+        let mut buffer = [0.0; 256];
+        while !consumer.is_finished() {
+            let read = consumer.pop(&mut buffer);
+            after.extend_from_slice(&buffer[..read]);
+        }
+        (before, after)
+    }
+
+    #[test]
+    fn speed_change_continues_where_playback_is() {
+        let (whole, rest) = play(RATE as usize, 1.0);
+        let (before, after) = play(RATE as usize / 2, 1.0);
+        let whole: Vec<f32> = whole.into_iter().chain(rest).collect();
+        let resumed: Vec<f32> = before.into_iter().chain(after).collect();
+        assert_eq!(resumed.len(), 4 * RATE as usize * 2);
+        assert_eq!(whole, resumed);
+    }
+
+    #[test]
+    fn speed_change_applies_to_what_is_left() {
+        let (_, after) = play(RATE as usize, 2.0);
+        // Three seconds were left, played in about one and a half
+        let frames = after.len() / 2;
+        let expected = 3 * RATE as usize / 2;
+        assert!(frames.abs_diff(expected) < RATE as usize / 20, "{frames}");
+    }
+}
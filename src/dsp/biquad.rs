@@ -0,0 +1,157 @@
+use std::f64::consts::PI;
+
+/// A second order IIR filter in transposed direct form II. Coefficients are
+/// normalized so `a[0]` is 1.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: a.map(|a_n| a_n / a[0]),
+            z: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+
+    /// Takes the coefficients of another filter, keeping the state so a
+    /// change doesn't click
+    pub fn retune(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn reset(&mut self) {
+        self.z = [0.0; 2];
+    }
+
+    // The filters below follow the Audio EQ Cookbook by Robert
+    // Bristow-Johnson. Gains are in dB.
+
+    pub fn peaking(rate: f64, freq: f64, gain: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(rate, freq, q);
+        let a = 10f64.powf(gain / 40.0);
+        Self::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(rate: f64, freq: f64, gain: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(rate, freq, q);
+        let a = 10f64.powf(gain / 40.0);
+        let k = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + k),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - k),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + k,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - k,
+            ],
+        )
+    }
+
+    pub fn high_shelf(rate: f64, freq: f64, gain: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(rate, freq, q);
+        let a = 10f64.powf(gain / 40.0);
+        let k = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + k),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - k),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + k,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - k,
+            ],
+        )
+    }
+
+    pub fn low_pass(rate: f64, freq: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(rate, freq, q);
+        Self::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn high_pass(rate: f64, freq: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(rate, freq, q);
+        Self::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// cos(w0) and alpha for a centre frequency, kept below Nyquist
+    fn omega(rate: f64, freq: f64, q: f64) -> (f64, f64) {
+        let w0 = 2.0 * PI * freq.clamp(1.0, rate * 0.49) / rate;
+        (w0.cos(), w0.sin() / (2.0 * q.max(0.01)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    /// Gain of a filter in dB for a sine at `freq`, once it has settled
+    fn response(mut filter: Biquad, freq: f64) -> f64 {
+        let frames = RATE as usize;
+        let mut peak = 0f64;
+        for i in 0..frames {
+            let y = filter.process((2.0 * PI * freq * i as f64 / RATE).sin());
+            if i > frames / 2 {
+                peak = peak.max(y.abs());
+            }
+        }
+        20.0 * peak.log10()
+    }
+
+    #[test]
+    fn passes_are_3db_down_at_cutoff() {
+        let low = response(Biquad::low_pass(RATE, 1000.0, FRAC_1_SQRT_2), 1000.0);
+        let high = response(Biquad::high_pass(RATE, 1000.0, FRAC_1_SQRT_2), 1000.0);
+        assert!((low + 3.01).abs() < 0.05, "{low}");
+        assert!((high + 3.01).abs() < 0.05, "{high}");
+    }
+
+    #[test]
+    fn peaking_gain_at_centre() {
+        let centre = response(Biquad::peaking(RATE, 1000.0, 6.0, 1.0), 1000.0);
+        let far = response(Biquad::peaking(RATE, 1000.0, 6.0, 1.0), 20.0);
+        assert!((centre - 6.0).abs() < 0.05, "{centre}");
+        assert!(far.abs() < 0.1, "{far}");
+    }
+
+    #[test]
+    fn shelves_reach_their_gain() {
+        let low = response(Biquad::low_shelf(RATE, 200.0, -6.0, FRAC_1_SQRT_2), 20.0);
+        let high = response(
+            Biquad::high_shelf(RATE, 1000.0, 6.0, FRAC_1_SQRT_2),
+            10000.0,
+        );
+        assert!((low + 6.0).abs() < 0.2, "{low}");
+        assert!((high - 6.0).abs() < 0.2, "{high}");
+    }
+}
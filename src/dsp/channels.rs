@@ -0,0 +1,162 @@
+//! Stages that mix channels together. They expect interleaved frames and
+//! leave anything past the first two channels alone where noted.
+
+use std::f32::consts::PI;
+
+use super::Processor;
+
+/// Crossfed signal is low-passed here, roughly where the head stops
+/// shadowing sound from the other side
+const CROSSFEED_CUTOFF: f32 = 700.0;
+
+/// Replaces every channel with the average of all of them
+pub struct MonoDownmix {
+    channels: usize,
+}
+
+impl Default for MonoDownmix {
+    fn default() -> Self {
+        Self { channels: 2 }
+    }
+}
+
+impl Processor for MonoDownmix {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let mono = frame.iter().sum::<f32>() / self.channels as f32;
+            frame.fill(mono);
+        }
+    }
+
+    fn set_format(&mut self, _rate: u32, channels: usize) {
+        self.channels = channels.max(1);
+    }
+}
+
+/// Swaps the first two channels
+pub struct ChannelSwap {
+    channels: usize,
+}
+
+impl Default for ChannelSwap {
+    fn default() -> Self {
+        Self { channels: 2 }
+    }
+}
+
+impl Processor for ChannelSwap {
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.channels < 2 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(self.channels) {
+            frame.swap(0, 1);
+        }
+    }
+
+    fn set_format(&mut self, _rate: u32, channels: usize) {
+        self.channels = channels.max(1);
+    }
+}
+
+/// Feeds a low-passed copy of each of the first two channels into the other,
+/// which makes hard panned mixes less tiring on headphones.
+pub struct Crossfeed {
+    level: f32,
+    channels: usize,
+    /// One pole low-pass coefficient
+    coefficient: f32,
+    /// Low-passed left and right
+    state: [f32; 2],
+}
+
+impl Crossfeed {
+    pub fn new(level: f32) -> Self {
+        Self {
+            level,
+            channels: 2,
+            coefficient: 0.0,
+            state: [0.0; 2],
+        }
+    }
+}
+
+impl Processor for Crossfeed {
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.channels < 2 {
+            return;
+        }
+        // Keeps a centred signal at the same level
+        let norm = 1.0 / (1.0 + self.level);
+
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let (left, right) = (frame[0], frame[1]);
+            self.state[0] += self.coefficient * (left - self.state[0]);
+            self.state[1] += self.coefficient * (right - self.state[1]);
+
+            frame[0] = (left + self.level * self.state[1]) * norm;
+            frame[1] = (right + self.level * self.state[0]) * norm;
+        }
+    }
+
+    fn set_format(&mut self, rate: u32, channels: usize) {
+        self.channels = channels.max(1);
+        self.coefficient = 1.0 - (-2.0 * PI * CROSSFEED_CUTOFF / rate as f32).exp();
+    }
+
+    fn reset(&mut self) {
+        self.state = [0.0; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap() {
+        let mut swap = ChannelSwap::default();
+        swap.set_format(48000, 3);
+        let mut samples = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        swap.process(&mut samples);
+        assert_eq!(samples, [2.0, 1.0, 3.0, 5.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn downmix() {
+        let mut mono = MonoDownmix::default();
+        mono.set_format(48000, 2);
+        let mut samples = [1.0, 0.0, 0.5, -0.5];
+        mono.process(&mut samples);
+        assert_eq!(samples, [0.5, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn process_before_set_format() {
+        let mut samples = [1.0, 0.0];
+        MonoDownmix::default().process(&mut samples);
+        ChannelSwap::default().process(&mut samples);
+        assert_eq!(samples, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn crossfeed() {
+        let level = 0.3;
+        let mut crossfeed = Crossfeed::new(level);
+        crossfeed.set_format(48000, 2);
+
+        // Once the low-pass settles, a hard panned constant bleeds across
+        let mut samples: Vec<f32> = [1.0, 0.0].repeat(4800);
+        crossfeed.process(&mut samples);
+        let last = &samples[samples.len() - 2..];
+        assert!((last[0] - 1.0 / (1.0 + level)).abs() < 1e-4, "{last:?}");
+        assert!((last[1] - level / (1.0 + level)).abs() < 1e-4, "{last:?}");
+
+        // and a centred one keeps its level
+        crossfeed.reset();
+        let mut samples: Vec<f32> = [0.5, 0.5].repeat(4800);
+        crossfeed.process(&mut samples);
+        let last = &samples[samples.len() - 2..];
+        assert!(last.iter().all(|s| (s - 0.5).abs() < 1e-4), "{last:?}");
+    }
+}
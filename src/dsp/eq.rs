@@ -0,0 +1,479 @@
+//! A multi-band parametric equalizer.
+//!
+//! Profiles use the EqualizerAPO text format, which is also what AutoEQ
+//! publishes its headphone corrections in:
+//!
+//! ```text
+//! Preamp: -6.2 dB
+//! Filter 1: ON PK Fc 105 Hz Gain 3.9 dB Q 0.70
+//! Filter 2: ON HSC Fc 10000 Hz Gain -2.0 dB Q 0.70
+//! ```
+
+use std::{
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::{debug, warn};
+
+use super::{biquad::Biquad, Processor};
+use crate::state;
+
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+/// Loaded at startup when no `--eq` is given
+const CONFIG_FILE: &str = "eq.txt";
+/// User presets live in this directory under the config directory
+const PRESET_DIR: &str = "eq";
+
+const PRESETS: &[(&str, &str)] = &[
+    ("flat", ""),
+    ("bass", "Preamp: -6 dB\nFilter 1: ON LSC Fc 105 Hz Gain 6 dB Q 0.71"),
+    ("treble", "Preamp: -4 dB\nFilter 1: ON HSC Fc 8000 Hz Gain 4 dB Q 0.71"),
+    (
+        "vocal",
+        "Preamp: -3 dB\nFilter 1: ON PK Fc 250 Hz Gain -2 dB Q 1.0\nFilter 2: ON PK Fc 3000 Hz Gain 3 dB Q 1.0",
+    ),
+    (
+        "loudness",
+        "Preamp: -6 dB\nFilter 1: ON LSC Fc 100 Hz Gain 6 dB Q 0.71\nFilter 2: ON HSC Fc 10000 Hz Gain 4 dB Q 0.71",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pk" | "peq" | "peaking" => Ok(Self::Peaking),
+            "ls" | "lsc" | "lowshelf" => Ok(Self::LowShelf),
+            "hs" | "hsc" | "highshelf" => Ok(Self::HighShelf),
+            "lp" | "lpq" | "lowpass" => Ok(Self::LowPass),
+            "hp" | "hpq" | "highpass" => Ok(Self::HighPass),
+            _ => Err(format!("Unknown filter type: {s}")),
+        }
+    }
+}
+
+impl Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Peaking => "PK",
+            Self::LowShelf => "LSC",
+            Self::HighShelf => "HSC",
+            Self::LowPass => "LPQ",
+            Self::HighPass => "HPQ",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// One filter of the equalizer. Gain is in dB and ignored by the passes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub kind: FilterKind,
+    pub freq: f32,
+    pub gain: f32,
+    pub q: f32,
+}
+
+impl Default for Band {
+    fn default() -> Self {
+        Self {
+            kind: FilterKind::Peaking,
+            freq: 1000.0,
+            gain: 0.0,
+            q: 1.0,
+        }
+    }
+}
+
+impl Band {
+    fn filter(&self, rate: u32) -> Biquad {
+        let (rate, freq, gain, q) = (
+            rate as f64,
+            self.freq as f64,
+            self.gain as f64,
+            self.q as f64,
+        );
+        match self.kind {
+            FilterKind::Peaking => Biquad::peaking(rate, freq, gain, q),
+            FilterKind::LowShelf => Biquad::low_shelf(rate, freq, gain, q),
+            FilterKind::HighShelf => Biquad::high_shelf(rate, freq, gain, q),
+            FilterKind::LowPass => Biquad::low_pass(rate, freq, q),
+            FilterKind::HighPass => Biquad::high_pass(rate, freq, q),
+        }
+    }
+
+    /// Parses the part of an EqualizerAPO filter line after the colon. None
+    /// if the filter is switched off.
+    fn parse(line: &str) -> Result<Option<Self>, String> {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("ON") => {}
+            Some("OFF") => return Ok(None),
+            _ => return Err(format!("Expected ON or OFF: {line}")),
+        }
+
+        let kind = tokens.next().ok_or("Expected a filter type")?.parse()?;
+        let mut band = Self {
+            kind,
+            q: DEFAULT_Q,
+            ..Self::default()
+        };
+        while let Some(token) = tokens.next() {
+            let mut value = || -> Result<f32, String> {
+                let value = tokens
+                    .next()
+                    .ok_or(format!("Expected a value for {token}"))?;
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {token}: {value}"))
+            };
+            match token {
+                "Fc" => band.freq = value()?,
+                "Gain" => band.gain = value()?,
+                "Q" => band.q = value()?,
+                // Units
+                "Hz" | "dB" => {}
+                _ => return Err(format!("Unsupported filter parameter: {token}")),
+            }
+        }
+        Ok(Some(band))
+    }
+}
+
+/// A change requested by a client, `eq band 3 gain -2` and the like. Bands
+/// are numbered from 1 like in profiles.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EqChange {
+    Enable(bool),
+    Preset(String),
+    Load(PathBuf),
+    Preamp(f32),
+    Band(usize, BandChange),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BandChange {
+    Kind(FilterKind),
+    Freq(f32),
+    Gain(f32),
+    Q(f32),
+    Remove,
+}
+
+impl FromStr for EqChange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let mut next = |what| parts.next().ok_or(format!("Expected {what}"));
+        let number = |s: &str| s.parse::<f32>().map_err(|_| format!("Invalid number: {s}"));
+
+        match next("an eq command")? {
+            "on" => Ok(Self::Enable(true)),
+            "off" => Ok(Self::Enable(false)),
+            "preset" => Ok(Self::Preset(next("a preset name")?.to_string())),
+            "load" => {
+                // Paths may contain spaces, so take the rest of the line
+                let path = s.trim_start()["load".len()..].trim();
+                if path.is_empty() {
+                    return Err("Expected a path".to_string());
+                }
+                Ok(Self::Load(path.into()))
+            }
+            "preamp" => Ok(Self::Preamp(number(next("a gain")?)?)),
+            "band" => {
+                let band = next("a band number")?;
+                let band = band.parse().map_err(|_| format!("Invalid band: {band}"))?;
+                let change = match next("a band parameter")? {
+                    "type" => BandChange::Kind(next("a filter type")?.parse()?),
+                    "freq" | "fc" => BandChange::Freq(number(next("a frequency")?)?),
+                    "gain" => BandChange::Gain(number(next("a gain")?)?),
+                    "q" => BandChange::Q(number(next("a Q")?)?),
+                    "remove" => BandChange::Remove,
+                    p => return Err(format!("Unknown band parameter: {p}")),
+                };
+                Ok(Self::Band(band, change))
+            }
+            c => Err(format!("Unknown eq command: {c}")),
+        }
+    }
+}
+
+/// The equalizer as the user configured it, independent of the sample rate
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EqSettings {
+    pub enabled: bool,
+    /// Gain applied before the filters, in dB
+    pub preamp: f32,
+    pub bands: Vec<Band>,
+}
+
+impl EqSettings {
+    /// Parses an EqualizerAPO profile. Lines other than `Preamp` and `Filter`
+    /// are ignored.
+    pub fn parse(profile: &str) -> Result<Self, String> {
+        let mut settings = Self {
+            enabled: true,
+            ..Self::default()
+        };
+
+        for line in profile.lines().map(str::trim) {
+            let Some((key, rest)) = line.split_once(':') else {
+                continue;
+            };
+
+            if key == "Preamp" {
+                let gain = rest.trim().trim_end_matches("dB").trim();
+                settings.preamp = gain
+                    .parse()
+                    .map_err(|_| format!("Invalid preamp: {gain}"))?;
+            } else if key.starts_with("Filter") {
+                settings.bands.extend(Band::parse(rest)?);
+            } else if !key.starts_with('#') {
+                debug!("Ignoring unsupported profile line: {line}");
+            }
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// A preset from the config directory, or a built-in one
+    pub fn preset(name: &str) -> Result<Self, Box<dyn Error>> {
+        let path = state::config_dir().map(|d| d.join(PRESET_DIR).join(format!("{name}.txt")));
+        if let Some(path) = path.filter(|p| p.exists()) {
+            return Self::load(&path);
+        }
+
+        let (_, profile) = PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .ok_or(format!("Unknown eq preset: {name}"))?;
+        Ok(Self::parse(profile)?)
+    }
+
+    /// The equalizer to start with: `--eq` if given, which is a path or a
+    /// preset name, otherwise the config file if there is one
+    pub fn startup(eq: Option<&str>) -> Self {
+        let loaded = match eq {
+            Some(eq) if Path::new(eq).is_file() => Self::load(Path::new(eq)),
+            Some(eq) => Self::preset(eq),
+            None => match state::config_dir().map(|d| d.join(CONFIG_FILE)) {
+                Some(path) if path.exists() => Self::load(&path),
+                _ => return Self::default(),
+            },
+        };
+
+        loaded.unwrap_or_else(|e| {
+            warn!("Failed to load equalizer, starting without it: {e}");
+            Self::default()
+        })
+    }
+
+    pub fn apply(&mut self, change: EqChange) -> Result<(), Box<dyn Error>> {
+        match change {
+            EqChange::Enable(enabled) => self.enabled = enabled,
+            EqChange::Preset(name) => *self = Self::preset(&name)?,
+            EqChange::Load(path) => *self = Self::load(&path)?,
+            EqChange::Preamp(gain) => {
+                self.preamp = gain;
+                self.enabled = true;
+            }
+            EqChange::Band(n, change) => {
+                // Changing the band after the last one adds a new band
+                if n == self.bands.len() + 1 && change != BandChange::Remove {
+                    self.bands.push(Band::default());
+                }
+                let index = n.checked_sub(1).filter(|i| *i < self.bands.len());
+                let index = index.ok_or(format!("No band {n}"))?;
+                // Shaping the equalizer means wanting to hear it
+                self.enabled = true;
+
+                let band = &mut self.bands[index];
+                match change {
+                    BandChange::Kind(kind) => band.kind = kind,
+                    BandChange::Freq(freq) => band.freq = freq,
+                    BandChange::Gain(gain) => band.gain = gain,
+                    BandChange::Q(q) => band.q = q,
+                    BandChange::Remove => {
+                        self.bands.remove(index);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the equalizer over interleaved samples at one sample rate. It sits in
+/// front of the chain rather than in it so its bands can be adjusted without
+/// losing filter state.
+pub struct Equalizer {
+    settings: EqSettings,
+    rate: u32,
+    channels: usize,
+    preamp: f32,
+    /// One filter per band per channel
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl Equalizer {
+    pub fn new(settings: EqSettings, rate: u32, channels: usize) -> Self {
+        let mut eq = Self {
+            settings: EqSettings::default(),
+            rate,
+            channels,
+            preamp: 1.0,
+            filters: vec![],
+        };
+        eq.set(settings);
+        eq
+    }
+
+    /// Switches to new settings. Filters that are still there keep their
+    /// state so adjusting a band doesn't click.
+    pub fn set(&mut self, settings: EqSettings) {
+        self.filters.truncate(settings.bands.len());
+        for (i, band) in settings.bands.iter().enumerate() {
+            let filter = band.filter(self.rate);
+            match self.filters.get_mut(i) {
+                Some(filters) => filters.iter_mut().for_each(|f| f.retune(&filter)),
+                None => self.filters.push(vec![filter; self.channels]),
+            }
+        }
+
+        self.preamp = 10f32.powf(settings.preamp / 20.0);
+        self.settings = settings;
+    }
+}
+
+impl Processor for Equalizer {
+    fn process(&mut self, samples: &mut [f32]) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = (*sample * self.preamp) as f64;
+                for filters in self.filters.iter_mut() {
+                    x = filters[channel].process(x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+
+    fn set_format(&mut self, rate: u32, channels: usize) {
+        self.rate = rate;
+        self.channels = channels.max(1);
+        self.filters.clear();
+        self.set(self.settings.clone());
+    }
+
+    fn reset(&mut self) {
+        self.filters.iter_mut().flatten().for_each(Biquad::reset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Peak of a stereo sine at `freq` after the equalizer settled
+    fn peak(eq: &mut Equalizer, freq: f32) -> f32 {
+        let mut samples: Vec<f32> = (0..48000)
+            .flat_map(|i| {
+                let s = (2.0 * std::f32::consts::PI * freq * i as f32 / 48000.0).sin() * 0.25;
+                [s, s]
+            })
+            .collect();
+        eq.process(&mut samples);
+        samples[48000..]
+            .iter()
+            .fold(0f32, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let settings = EqSettings::parse(
+            "Preamp: -3 dB\n\
+             Filter 1: ON PK Fc 1000 Hz Gain 4 dB Q 1.41\n\
+             Filter 2: OFF LSC Fc 100 Hz Gain 2 dB\n\
+             Filter 3: ON HP Fc 30 Hz",
+        )
+        .unwrap();
+        assert!(settings.enabled);
+        assert_eq!(settings.preamp, -3.0);
+        assert_eq!(settings.bands.len(), 2);
+        assert_eq!(settings.bands[0].kind, FilterKind::Peaking);
+        assert_eq!(settings.bands[0].gain, 4.0);
+        assert_eq!(settings.bands[1].kind, FilterKind::HighPass);
+        assert_eq!(settings.bands[1].q, DEFAULT_Q);
+    }
+
+    #[test]
+    fn boosts_band() {
+        let settings = EqSettings::parse("Filter: ON PK Fc 1000 Hz Gain 6 dB Q 1").unwrap();
+        let mut eq = Equalizer::new(settings.clone(), 48000, 2);
+        let boosted = peak(&mut eq, 1000.0) / 0.25;
+        assert!((20.0 * boosted.log10() - 6.0).abs() < 0.1, "{boosted}");
+
+        let mut bypassed = Equalizer::new(
+            EqSettings {
+                enabled: false,
+                ..settings
+            },
+            48000,
+            2,
+        );
+        assert!((peak(&mut bypassed, 1000.0) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn band_changes() {
+        let mut settings = EqSettings::default();
+        assert!(settings
+            .apply(EqChange::Band(3, BandChange::Q(2.0)))
+            .is_err());
+        assert!(!settings.enabled);
+        settings
+            .apply(EqChange::Band(1, BandChange::Gain(3.0)))
+            .unwrap();
+        assert_eq!(settings.bands.len(), 1);
+        assert!(settings.enabled);
+        settings
+            .apply(EqChange::Band(1, BandChange::Remove))
+            .unwrap();
+        assert!(settings.bands.is_empty());
+    }
+
+    #[test]
+    fn parses_changes() {
+        assert_eq!(
+            "band 2 gain -1.5".parse(),
+            Ok(EqChange::Band(2, BandChange::Gain(-1.5)))
+        );
+        assert_eq!(
+            "load  ~/My Profiles/hd650.txt ".parse(),
+            Ok(EqChange::Load("~/My Profiles/hd650.txt".into()))
+        );
+        assert!("load".parse::<EqChange>().is_err());
+    }
+}
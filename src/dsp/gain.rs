@@ -0,0 +1,99 @@
+use super::Processor;
+
+/// How long the limiter takes to recover after reducing the gain
+const LIMITER_RELEASE_SECONDS: f32 = 0.1;
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// A fixed gain
+pub struct Gain {
+    factor: f32,
+}
+
+impl Gain {
+    pub fn new(db: f32) -> Self {
+        Self {
+            factor: db_to_linear(db),
+        }
+    }
+}
+
+impl Processor for Gain {
+    fn process(&mut self, samples: &mut [f32]) {
+        samples.iter_mut().for_each(|s| *s *= self.factor);
+    }
+
+    fn set_format(&mut self, _rate: u32, _channels: usize) {}
+}
+
+/// Keeps peaks under a ceiling. Reduces the gain instantly when a frame
+/// would go over and lets it recover exponentially.
+pub struct Limiter {
+    ceiling: f32,
+    channels: usize,
+    /// Per frame factor the gain recovers by
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(ceiling_db: f32) -> Self {
+        Self {
+            ceiling: db_to_linear(ceiling_db),
+            channels: 1,
+            release: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Processor for Limiter {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            self.gain = 1.0 - (1.0 - self.gain) * self.release;
+
+            let peak = frame.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+            if peak * self.gain > self.ceiling {
+                self.gain = self.ceiling / peak;
+            }
+            frame.iter_mut().for_each(|s| *s *= self.gain);
+        }
+    }
+
+    fn set_format(&mut self, rate: u32, channels: usize) {
+        self.channels = channels.max(1);
+        // Recovers ~63% of the way back to unity over the release time
+        self.release = (-1.0 / (LIMITER_RELEASE_SECONDS * rate as f32)).exp();
+    }
+
+    fn reset(&mut self) {
+        self.gain = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_in_db() {
+        let mut gain = Gain::new(-6.0);
+        gain.set_format(48000, 2);
+        let mut samples = [1.0, -0.5];
+        gain.process(&mut samples);
+        assert!((samples[0] - 0.501187).abs() < 1e-5);
+        assert!((samples[1] + 0.250594).abs() < 1e-5);
+    }
+
+    #[test]
+    fn limiter_keeps_peaks_under_ceiling() {
+        let mut limiter = Limiter::new(-1.0);
+        limiter.set_format(48000, 2);
+        let mut samples: Vec<f32> = (0..9600).map(|i| (i as f32 * 0.1).sin() * 2.0).collect();
+        limiter.process(&mut samples);
+        let ceiling = db_to_linear(-1.0);
+        assert!(samples.iter().all(|s| s.abs() <= ceiling + 1e-6));
+    }
+}
@@ -0,0 +1,259 @@
+//! Playback speed changes.
+//!
+//! Unlike the stages of the chain these change how many samples come out, so
+//! the decoder runs them on its own right after decoding.
+
+use std::{f32::consts::PI, fmt::Display, str::FromStr};
+
+/// Length of the windows the time-stretcher overlaps
+const WINDOW_SECONDS: f32 = 0.03;
+/// How far the time-stretcher may shift a window to line it up with the last
+const TOLERANCE_SECONDS: f32 = 0.01;
+/// Only every nth frame is compared when lining windows up
+const SEARCH_STEP: usize = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpeedMode {
+    /// Keeps the pitch by stretching time
+    #[default]
+    Stretch,
+    /// Resamples, so the pitch follows the speed like a tape
+    Pitch,
+}
+
+impl FromStr for SpeedMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(Self::Stretch),
+            "pitch" => Ok(Self::Pitch),
+            _ => Err(format!("Unknown speed mode: {s}")),
+        }
+    }
+}
+
+impl Display for SpeedMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stretch => write!(f, "stretch"),
+            Self::Pitch => write!(f, "pitch"),
+        }
+    }
+}
+
+/// Changes the speed of interleaved samples
+pub struct Speed {
+    speed: f32,
+    mode: SpeedMode,
+    channels: usize,
+    /// Input that hasn't been consumed yet
+    input: Vec<f32>,
+    /// Position of the next read in `input`, in frames
+    position: f64,
+    /// Time-stretch window length and hop between output windows, in frames
+    window: usize,
+    hop: usize,
+    tolerance: usize,
+    hann: Vec<f32>,
+    /// Second half of the last window, waiting for the next one to overlap it
+    overlap: Vec<f32>,
+    /// Where the last window would have continued in `input`, in frames
+    continuation: Option<usize>,
+}
+
+impl Speed {
+    pub fn new(speed: f32, mode: SpeedMode, rate: u32, channels: usize) -> Self {
+        let hop = ((WINDOW_SECONDS * rate as f32) as usize / 2).max(1);
+        let window = hop * 2;
+        let hann = (0..window)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / window as f32).cos())
+            .collect();
+
+        Self {
+            speed,
+            mode,
+            channels,
+            input: vec![],
+            position: 0.0,
+            window,
+            hop,
+            tolerance: (TOLERANCE_SECONDS * rate as f32) as usize,
+            hann,
+            overlap: vec![0.0; hop * channels],
+            continuation: None,
+        }
+    }
+
+    /// Nothing to do at normal speed
+    pub fn is_bypassed(&self) -> bool {
+        self.speed == 1.0
+    }
+
+    /// Feeds samples in and appends whatever comes out to `output`
+    pub fn process(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+        if self.is_bypassed() {
+            output.extend_from_slice(samples);
+            return;
+        }
+
+        self.input.extend_from_slice(samples);
+        match self.mode {
+            SpeedMode::Stretch => self.stretch(output),
+            SpeedMode::Pitch => self.resample(output),
+        }
+    }
+
+    /// Appends what is still held back once there is no more input
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if self.mode == SpeedMode::Stretch && self.continuation.is_some() {
+            output.extend_from_slice(&self.overlap);
+        }
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.position = 0.0;
+        self.overlap.fill(0.0);
+        self.continuation = None;
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    /// Drops input before `frame`
+    fn consume(&mut self, frame: usize) {
+        self.input.drain(..frame * self.channels);
+        self.position -= frame as f64;
+        self.continuation = self.continuation.map(|c| c - frame);
+    }
+
+    /// Linear interpolation, stepping through the input `speed` frames at a
+    /// time
+    fn resample(&mut self, output: &mut Vec<f32>) {
+        let channels = self.channels;
+        while (self.position as usize) + 1 < self.frames() {
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+            for c in 0..channels {
+                let a = self.input[index * channels + c];
+                let b = self.input[(index + 1) * channels + c];
+                output.push(a + (b - a) * t);
+            }
+            self.position += self.speed as f64;
+        }
+        self.consume((self.position as usize).min(self.frames()));
+    }
+
+    /// WSOLA: overlap-adds Hann windows a fixed hop apart in the output while
+    /// reading them `speed` hops apart from the input. Each window is shifted
+    /// a little to best match how the last one would have continued, which
+    /// keeps the waveform from cancelling itself out.
+    fn stretch(&mut self, output: &mut Vec<f32>) {
+        let channels = self.channels;
+        loop {
+            let nominal = self.position as usize;
+            // The search may look `tolerance` frames either side
+            if nominal + self.tolerance + self.window > self.frames() {
+                break;
+            }
+
+            let start = match self.continuation {
+                Some(continuation) => self.best_match(nominal, continuation),
+                None => nominal,
+            };
+
+            for i in 0..self.window {
+                let w = self.hann[i];
+                for c in 0..channels {
+                    let sample = self.input[(start + i) * channels + c] * w;
+                    if i < self.hop {
+                        output.push(self.overlap[i * channels + c] + sample);
+                    } else {
+                        self.overlap[(i - self.hop) * channels + c] = sample;
+                    }
+                }
+            }
+
+            self.continuation = Some(start + self.hop);
+            self.position += self.hop as f64 * self.speed as f64;
+
+            // Keep enough behind for the search to look back
+            let keep = (self.position as usize)
+                .saturating_sub(self.tolerance)
+                .min(self.continuation.unwrap_or(0));
+            self.consume(keep);
+        }
+    }
+
+    /// The start near `nominal` whose first hop correlates best with the hop
+    /// at `continuation`
+    fn best_match(&self, nominal: usize, continuation: usize) -> usize {
+        let channels = self.channels;
+        let mono = |frame: usize| -> f32 {
+            self.input[frame * channels..(frame + 1) * channels]
+                .iter()
+                .sum()
+        };
+
+        let lowest = nominal.saturating_sub(self.tolerance);
+        let highest = nominal + self.tolerance;
+        let mut best = (nominal, f32::MIN);
+        for start in (lowest..=highest).step_by(SEARCH_STEP) {
+            let correlation: f32 = (0..self.hop)
+                .step_by(SEARCH_STEP)
+                .map(|i| mono(start + i) * mono(continuation + i))
+                .sum();
+            if correlation > best.1 {
+                best = (start, correlation);
+            }
+        }
+        best.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames out of a second of stereo sine fed in small chunks
+    fn output_frames(speed: f32, mode: SpeedMode) -> usize {
+        let mut stage = Speed::new(speed, mode, 48000, 2);
+        let input: Vec<f32> = (0..48000)
+            .flat_map(|i| {
+                let s = (i as f32 * 0.05).sin();
+                [s, s]
+            })
+            .collect();
+        let mut output = vec![];
+        for chunk in input.chunks(1152 * 2) {
+            stage.process(chunk, &mut output);
+        }
+        stage.flush(&mut output);
+        output.len() / 2
+    }
+
+    #[test]
+    fn bypassed_at_normal_speed() {
+        assert_eq!(output_frames(1.0, SpeedMode::Stretch), 48000);
+    }
+
+    #[test]
+    fn output_length_follows_speed() {
+        // Up to a window and the search around it is held back at the end
+        let held = ((WINDOW_SECONDS + TOLERANCE_SECONDS) * 48000.0) as usize;
+        for mode in [SpeedMode::Stretch, SpeedMode::Pitch] {
+            for speed in [0.5, 1.5, 2.0] {
+                let expected = (48000.0 / speed) as usize;
+                let slack = (held as f32 / speed) as usize;
+                let frames = output_frames(speed, mode);
+                assert!(
+                    frames.abs_diff(expected) <= slack,
+                    "{mode} at {speed}: {frames} frames, expected {expected}"
+                );
+            }
+        }
+    }
+}
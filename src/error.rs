@@ -0,0 +1,65 @@
+//! Errors that are worth telling clients about
+
+use std::{fmt::Display, io};
+
+use symphonia::core::errors::Error as SymphoniaError;
+
+#[derive(Debug)]
+pub enum PlayerError {
+    /// A song couldn't be decoded
+    Decode(SymphoniaError),
+    Io(io::Error),
+    /// A song the player can't play, e.g. in a format no decoder handles
+    UnsupportedFormat(String),
+    Pipewire(String),
+    /// A client sent something that isn't a valid command
+    Protocol(String),
+    /// A command that can't be carried out with the queue as it is, e.g. a
+    /// missing bookmark or nothing playing
+    Queue(String),
+}
+
+impl Display for PlayerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(e) => write!(f, "Decode error: {e}"),
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::UnsupportedFormat(what) => write!(f, "Unsupported format: {what}"),
+            Self::Pipewire(e) => write!(f, "PipeWire error: {e}"),
+            Self::Protocol(e) => write!(f, "Protocol error: {e}"),
+            Self::Queue(e) => write!(f, "Queue error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PlayerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SymphoniaError> for PlayerError {
+    fn from(e: SymphoniaError) -> Self {
+        match e {
+            SymphoniaError::IoError(e) => Self::Io(e),
+            SymphoniaError::Unsupported(what) => Self::UnsupportedFormat(what.to_string()),
+            e => Self::Decode(e),
+        }
+    }
+}
+
+impl From<io::Error> for PlayerError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<pipewire::Error> for PlayerError {
+    fn from(e: pipewire::Error) -> Self {
+        Self::Pipewire(e.to_string())
+    }
+}
@@ -0,0 +1,47 @@
+//! Things that happen on their own while playing, sent to every client that
+//! asked for them with `subscribe`
+
+use std::{path::Path, sync::Mutex};
+
+use futures::channel::mpsc;
+use log::warn;
+
+use crate::error::PlayerError;
+
+static SUBSCRIBERS: Mutex<Vec<mpsc::UnboundedSender<String>>> = Mutex::new(Vec::new());
+
+/// Receives every event from now on as a line of text
+pub fn subscribe() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+fn publish(event: String) {
+    // Clients that went away are dropped here
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+}
+
+/// Logs an error that no command is waiting to hear about and tells
+/// subscribers
+pub fn error(e: &PlayerError) {
+    warn!("{e}");
+    publish(format!("event: error: {e}\n"));
+}
+
+/// Like `error`, for an error that is about the song at `path`
+pub fn song_error(path: &Path, e: &PlayerError) {
+    warn!("{}: {e}", path.display());
+    publish(format!("event: error: {}: {e}\n", path.display()));
+}
+
+/// A song moved on to another chapter, counted from 1
+pub fn chapter(number: usize, title: Option<&str>) {
+    publish(format!(
+        "event: chapter: {number} {}\n",
+        title.unwrap_or("")
+    ));
+}
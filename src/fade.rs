@@ -0,0 +1,145 @@
+//! Short gain ramps applied on the realtime thread to avoid clicks when
+//! playback starts or stops mid-song.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
+
+/// Shared between the pipewire loop, which decides whether we should be
+/// audible, and the realtime thread, which ramps towards that.
+pub struct FadeControl {
+    length_ms: AtomicU32,
+    audible: AtomicBool,
+    /// Set by the realtime thread once a fade-out has fully drained
+    silent: AtomicBool,
+}
+
+impl FadeControl {
+    pub fn new(length_ms: u32) -> Self {
+        Self {
+            length_ms: AtomicU32::new(length_ms),
+            audible: AtomicBool::new(true),
+            silent: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_length(&self, length_ms: u32) {
+        self.length_ms.store(length_ms, Ordering::Relaxed);
+    }
+
+    pub fn fade_in(&self) {
+        self.silent.store(false, Ordering::Relaxed);
+        self.audible.store(true, Ordering::Relaxed);
+    }
+
+    pub fn fade_out(&self) {
+        self.audible.store(false, Ordering::Relaxed);
+    }
+
+    /// True once a fade-out has reached silence
+    pub fn is_silent(&self) -> bool {
+        self.silent.load(Ordering::Relaxed)
+    }
+}
+
+/// The realtime side of a fade. Owns the current gain.
+pub struct FadeRamp {
+    control: Arc<FadeControl>,
+    rate: u32,
+    gain: f32,
+}
+
+impl FadeRamp {
+    pub fn new(control: Arc<FadeControl>, rate: u32) -> Self {
+        Self {
+            control,
+            rate,
+            gain: 1.0,
+        }
+    }
+
+    /// Ramps interleaved samples towards the target gain
+    pub fn apply(&mut self, samples: &mut [f32], channels: usize) {
+        let target = self.target();
+        if self.gain != target {
+            let step = self.step();
+            for frame in samples.chunks_exact_mut(channels) {
+                self.gain = Self::towards(self.gain, target, step);
+                frame.iter_mut().for_each(|s| *s *= self.gain);
+            }
+        } else if target == 0.0 {
+            samples.fill(0.0);
+        }
+        self.update_silent(target);
+    }
+
+    /// Moves the ramp on by frames that had no audio, so a fade-out still
+    /// drains while the decoder underruns
+    pub fn advance(&mut self, frames: usize) {
+        let target = self.target();
+        let step = self.step();
+        for _ in 0..frames {
+            if self.gain == target {
+                break;
+            }
+            self.gain = Self::towards(self.gain, target, step);
+        }
+        self.update_silent(target);
+    }
+
+    fn target(&self) -> f32 {
+        if self.control.audible.load(Ordering::Relaxed) {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn step(&self) -> f32 {
+        let length = self.control.length_ms.load(Ordering::Relaxed);
+        if length == 0 {
+            1.0
+        } else {
+            1000.0 / (length as f32 * self.rate as f32)
+        }
+    }
+
+    fn towards(gain: f32, target: f32, step: f32) -> f32 {
+        if target > gain {
+            (gain + step).min(target)
+        } else {
+            (gain - step).max(target)
+        }
+    }
+
+    fn update_silent(&self, target: f32) {
+        if self.gain == 0.0 && target == 0.0 {
+            self.control.silent.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fades_out_while_underrunning() {
+        let control = Arc::new(FadeControl::new(10));
+        let mut ramp = FadeRamp::new(control.clone(), 1000);
+        control.fade_out();
+
+        ramp.apply(&mut [], 2);
+        assert!(!control.is_silent());
+        // 10 ms at 1 kHz
+        ramp.advance(9);
+        assert!(!control.is_silent());
+        ramp.advance(1);
+        assert!(control.is_silent());
+
+        let mut samples = [1.0; 4];
+        ramp.apply(&mut samples, 2);
+        assert_eq!(samples, [0.0; 4]);
+    }
+}
@@ -0,0 +1,522 @@
+//! A small FLAC encoder for rendering: fixed predictors and Rice coded
+//! residuals, picked per block and channel for whatever comes out smallest.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Frames per block, the same as the reference encoder uses
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 24;
+const CHANNELS: usize = 2;
+/// Highest fixed predictor order the format has
+const MAX_ORDER: usize = 4;
+/// Highest Rice partition order tried
+const MAX_PARTITION_ORDER: u32 = 6;
+/// Rice parameter that marks an escaped partition with 5 bit parameters
+const RICE_ESCAPE: u64 = 31;
+/// STREAMINFO fields that are only known once everything is written
+const FRAME_SIZES_OFFSET: u64 = 12;
+const TOTAL_SAMPLES_OFFSET: u64 = 18;
+
+/// How the two channels are stored, as in the frame header
+#[derive(Clone, Copy)]
+enum Stereo {
+    Independent = 0b0001,
+    LeftSide = 0b1000,
+    RightSide = 0b1001,
+    MidSide = 0b1010,
+}
+
+/// Writes interleaved stereo f32 samples as 24-bit FLAC. Samples are clipped
+/// to full scale.
+pub struct FlacWriter {
+    file: BufWriter<File>,
+    rate: u32,
+    /// Samples waiting for a full block
+    pending: Vec<i32>,
+    frames: u64,
+    blocks: u32,
+    frame_sizes: Option<(usize, usize)>,
+}
+
+impl FlacWriter {
+    pub fn create(path: &Path, rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"fLaC")?;
+        // The only metadata block, so it's marked as the last one
+        file.write_all(&[0x80, 0, 0, 34])?;
+        file.write_all(&(BLOCK_SIZE as u16).to_be_bytes())?;
+        file.write_all(&(BLOCK_SIZE as u16).to_be_bytes())?;
+        // Frame sizes and the sample count are filled in by `finish`, and
+        // an MD5 of zeros means there is none
+        file.write_all(&[0; 6])?;
+        file.write_all(&Self::stream_info(rate, 0).to_be_bytes())?;
+        file.write_all(&[0; 16])?;
+
+        Ok(Self {
+            file,
+            rate,
+            pending: Vec::with_capacity(BLOCK_SIZE * CHANNELS),
+            frames: 0,
+            blocks: 0,
+            frame_sizes: None,
+        })
+    }
+
+    /// Frames written so far
+    pub fn frames(&self) -> u64 {
+        self.frames + (self.pending.len() / CHANNELS) as u64
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let full_scale = (1 << (BITS_PER_SAMPLE - 1)) as f32;
+        for sample in samples {
+            let sample = (sample * full_scale)
+                .round()
+                .clamp(-full_scale, full_scale - 1.0);
+            self.pending.push(sample as i32);
+            if self.pending.len() == BLOCK_SIZE * CHANNELS {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.write_block()?;
+        }
+
+        let (min, max) = self.frame_sizes.unwrap_or_default();
+        let mut sizes = [0; 6];
+        sizes[..3].copy_from_slice(&(min as u32).to_be_bytes()[1..]);
+        sizes[3..].copy_from_slice(&(max as u32).to_be_bytes()[1..]);
+        self.file.seek(SeekFrom::Start(FRAME_SIZES_OFFSET))?;
+        self.file.write_all(&sizes)?;
+        self.file.seek(SeekFrom::Start(TOTAL_SAMPLES_OFFSET))?;
+        self.file
+            .write_all(&Self::stream_info(self.rate, self.frames).to_be_bytes())?;
+        self.file.flush()
+    }
+
+    /// The STREAMINFO fields packed together with the sample count
+    fn stream_info(rate: u32, frames: u64) -> u64 {
+        (rate as u64) << 44
+            | ((CHANNELS as u64 - 1) << 41)
+            | ((BITS_PER_SAMPLE as u64 - 1) << 36)
+            | frames
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let frames = self.pending.len() / CHANNELS;
+        if self.frames + frames as u64 >= 1 << 36 {
+            let e = "The render is too long for a FLAC file";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+
+        let left: Vec<i64> = self.pending.iter().step_by(2).map(|&s| s as i64).collect();
+        let right: Vec<i64> = self
+            .pending
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|&s| s as i64)
+            .collect();
+        self.pending.clear();
+        let frame = encode_frame(self.blocks, &left, &right);
+
+        self.file.write_all(&frame)?;
+        self.frames += frames as u64;
+        self.blocks += 1;
+        // The last block may be shorter, so it doesn't count towards the
+        // smallest size, unless it's the only one
+        let (min, max) = self.frame_sizes.unwrap_or((frame.len(), frame.len()));
+        let min = if frames == BLOCK_SIZE {
+            min.min(frame.len())
+        } else {
+            min
+        };
+        self.frame_sizes = Some((min, max.max(frame.len())));
+        Ok(())
+    }
+}
+
+fn encode_frame(number: u32, left: &[i64], right: &[i64]) -> Vec<u8> {
+    let side: Vec<i64> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+    let mid: Vec<i64> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+
+    // Side needs an extra bit, so it's written as such
+    let bits = BITS_PER_SAMPLE;
+    let [left, right, side, mid] = [
+        Subframe::best(left, bits),
+        Subframe::best(right, bits),
+        Subframe::best(&side, bits + 1),
+        Subframe::best(&mid, bits),
+    ];
+    let (stereo, first, second) = [
+        (Stereo::Independent, &left, &right),
+        (Stereo::LeftSide, &left, &side),
+        (Stereo::RightSide, &side, &right),
+        (Stereo::MidSide, &mid, &side),
+    ]
+    .into_iter()
+    .min_by_key(|(_, first, second)| first.bits + second.bits)
+    .unwrap();
+
+    let mut out = BitWriter::default();
+    // Sync code, then a fixed block size
+    out.write(0b11111111111110, 14);
+    out.write(0, 2);
+    // Block size in 16 bits at the end of the header, sample rate from
+    // STREAMINFO
+    out.write(0b0111, 4);
+    out.write(0b0000, 4);
+    out.write(stereo as u64, 4);
+    out.write(0b110, 3);
+    out.write(0, 1);
+    write_utf8(&mut out, number);
+    out.write(first.samples.len() as u64 - 1, 16);
+    let crc = crc8(&out.bytes);
+    out.write(crc as u64, 8);
+
+    first.write(&mut out);
+    second.write(&mut out);
+    out.align();
+    let crc = crc16(&out.bytes);
+    out.write(crc as u64, 16);
+    out.bytes
+}
+
+/// One channel of a block, as whichever subframe type is smallest
+struct Subframe<'a> {
+    samples: &'a [i64],
+    bits_per_sample: u32,
+    kind: Kind,
+    /// Size once written
+    bits: u64,
+}
+
+enum Kind {
+    Constant,
+    Verbatim,
+    Fixed {
+        order: usize,
+        residual: Vec<i64>,
+        partition_order: u32,
+        parameters: Vec<u64>,
+    },
+}
+
+impl<'a> Subframe<'a> {
+    fn best(samples: &'a [i64], bits_per_sample: u32) -> Self {
+        // Type and wasted bits flag
+        const HEADER: u64 = 8;
+        let constant = Self {
+            samples,
+            bits_per_sample,
+            kind: Kind::Constant,
+            bits: HEADER + bits_per_sample as u64,
+        };
+        if samples.iter().all(|&s| s == samples[0]) {
+            return constant;
+        }
+
+        let mut best = Self {
+            bits: HEADER + bits_per_sample as u64 * samples.len() as u64,
+            kind: Kind::Verbatim,
+            ..constant
+        };
+        for order in 0..=MAX_ORDER.min(samples.len() - 1) {
+            let residual = fixed_residual(samples, order);
+            let (partition_order, parameters, residual_bits) = rice_partitions(&residual, order);
+            let bits = HEADER + bits_per_sample as u64 * order as u64 + residual_bits;
+            if bits < best.bits {
+                best = Self {
+                    samples,
+                    bits_per_sample,
+                    kind: Kind::Fixed {
+                        order,
+                        residual,
+                        partition_order,
+                        parameters,
+                    },
+                    bits,
+                };
+            }
+        }
+        best
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        let bits = self.bits_per_sample;
+        match &self.kind {
+            Kind::Constant => {
+                out.write(0b00000000, 8);
+                out.write_signed(self.samples[0], bits);
+            }
+            Kind::Verbatim => {
+                out.write(0b00000010, 8);
+                for &sample in self.samples {
+                    out.write_signed(sample, bits);
+                }
+            }
+            Kind::Fixed {
+                order,
+                residual,
+                partition_order,
+                parameters,
+            } => {
+                out.write(0b00010000 | (*order as u64) << 1, 8);
+                for &sample in &self.samples[..*order] {
+                    out.write_signed(sample, bits);
+                }
+                // Rice coding with 5 bit parameters
+                out.write(0b01, 2);
+                out.write(*partition_order as u64, 4);
+                let mut residual = residual.iter();
+                let partition = self.samples.len() >> partition_order;
+                for (i, &parameter) in parameters.iter().enumerate() {
+                    let count = if i == 0 { partition - order } else { partition };
+                    out.write(parameter, 5);
+                    for &r in residual.by_ref().take(count) {
+                        out.write_rice(zigzag(r), parameter);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What's left after predicting each sample from the ones before it with the
+/// fixed polynomial of `order`
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let mut residual = samples.to_vec();
+    // Each order is the difference of the one below
+    for o in 0..order {
+        for i in (o + 1..samples.len()).rev() {
+            residual[i] -= residual[i - 1];
+        }
+    }
+    residual.split_off(order)
+}
+
+/// Picks the partition order and the Rice parameter of each partition.
+/// Returns them with the size of the coded residual.
+fn rice_partitions(residual: &[i64], order: usize) -> (u32, Vec<u64>, u64) {
+    let block = residual.len() + order;
+    let mut best: Option<(u32, Vec<u64>, u64)> = None;
+
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partition = block >> partition_order;
+        // Partitions must split the block evenly and hold the warm-up
+        if partition << partition_order != block || partition <= order {
+            break;
+        }
+
+        let mut parameters = vec![];
+        // Coding method and partition order
+        let mut bits = 6;
+        let mut start = 0;
+        for i in 0..1 << partition_order {
+            let count = if i == 0 { partition - order } else { partition };
+            let values = &residual[start..start + count];
+            start += count;
+
+            let (parameter, size) = rice_parameter(values);
+            parameters.push(parameter);
+            bits += 5 + size;
+        }
+
+        if best.as_ref().is_none_or(|best| bits < best.2) {
+            best = Some((partition_order, parameters, bits));
+        }
+    }
+    best.unwrap()
+}
+
+/// The cheapest Rice parameter for `values` near their mean, and their size
+/// coded with it
+fn rice_parameter(values: &[i64]) -> (u64, u64) {
+    let size = |parameter: u64| -> u64 {
+        values
+            .iter()
+            .map(|&v| (zigzag(v) >> parameter) + 1 + parameter)
+            .sum()
+    };
+
+    let sum: u64 = values.iter().map(|&v| zigzag(v)).sum();
+    let mean = sum / values.len().max(1) as u64;
+    let guess = (u64::BITS - mean.leading_zeros()).saturating_sub(1) as u64;
+    (guess.saturating_sub(1)..=guess + 1)
+        .filter(|&parameter| parameter < RICE_ESCAPE)
+        .map(|parameter| (parameter, size(parameter)))
+        .min_by_key(|&(_, size)| size)
+        .unwrap()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Frame numbers are coded like UTF-8 characters
+fn write_utf8(out: &mut BitWriter, value: u32) {
+    if value < 0x80 {
+        out.write(value as u64, 8);
+        return;
+    }
+
+    // Each continuation byte holds 6 bits and takes one from the first
+    let mut continuation = 1;
+    while value >> (5 * continuation + 6) != 0 {
+        continuation += 1;
+    }
+    let leading = (0xff00u32 >> (continuation + 1)) as u64 & 0xff;
+    out.write(leading | (value >> (6 * continuation)) as u64, 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((value >> (6 * i)) & 0x3f) as u64, 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Packs bits most significant first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet making up a whole byte, in the low bits
+    partial: u64,
+    partial_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            self.partial = (self.partial << 1) | ((value >> i) & 1);
+            self.partial_bits += 1;
+            if self.partial_bits == 8 {
+                self.bytes.push(self.partial as u8);
+                self.partial = 0;
+                self.partial_bits = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1 << bits) - 1), bits);
+    }
+
+    /// The quotient in unary, then the remainder
+    fn write_rice(&mut self, value: u64, parameter: u64) {
+        let mut quotient = value >> parameter;
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient as u32 + 1);
+        self.write(value & ((1 << parameter) - 1), parameter as u32);
+    }
+
+    fn align(&mut self) {
+        if self.partial_bits > 0 {
+            self.write(0, 8 - self.partial_bits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::SongReader;
+
+    fn decode(path: &Path) -> Vec<f32> {
+        let mut song = SongReader::open(path).unwrap();
+        assert_eq!((song.rate, song.channels), (44100, 2));
+        let mut samples = vec![];
+        while let Ok(chunk) = song.next_chunk() {
+            samples.extend_from_slice(chunk);
+        }
+        samples
+    }
+
+    #[test]
+    fn decodes_to_what_was_written() {
+        let path = std::env::temp_dir().join(format!("pwplayer-flac-{}.flac", std::process::id()));
+        let full_scale = (1 << (BITS_PER_SAMPLE - 1)) as f32;
+        // Silence, a sine, noise and clipping, over several blocks with a
+        // short one at the end
+        let mut noise = 1u32;
+        let samples: Vec<f32> = (0..BLOCK_SIZE * 3 + 100)
+            .flat_map(|i| {
+                noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+                let sine = (i as f32 * 0.01).sin() * 0.5;
+                match i / BLOCK_SIZE {
+                    0 => [0.0, 0.0],
+                    1 => [sine, -sine],
+                    2 => [sine, (noise >> 8) as f32 / (1 << 24) as f32 - 0.5],
+                    _ => [1.5, -1.5],
+                }
+            })
+            .collect();
+
+        let mut writer = FlacWriter::create(&path, 44100).unwrap();
+        writer.write(&samples).unwrap();
+        assert_eq!(writer.frames(), 3 * BLOCK_SIZE as u64 + 100);
+        writer.finish().unwrap();
+
+        let expected: Vec<f32> = samples
+            .iter()
+            .map(|s| {
+                (s * full_scale)
+                    .round()
+                    .clamp(-full_scale, full_scale - 1.0)
+                    / full_scale
+            })
+            .collect();
+        assert_eq!(decode(&path), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn frame_numbers_code_like_utf8() {
+        for (value, expected) in [
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0xc2, 0x80]),
+            (0x800, vec![0xe0, 0xa0, 0x80]),
+        ] {
+            let mut out = BitWriter::default();
+            write_utf8(&mut out, value);
+            assert_eq!(out.bytes, expected);
+        }
+    }
+}
@@ -0,0 +1,745 @@
+//! Songs streamed over HTTP(S), e.g. internet radio from Icecast.
+//!
+//! A thread downloads into a buffer that the decoder reads from, picks the
+//! connection back up when it drops and pulls the ICY metadata that stations
+//! send the current title in out of the audio.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use native_tls::{TlsConnector, TlsStream};
+use symphonia::core::io::MediaSource;
+
+/// Bytes downloaded ahead of the decoder
+const BUFFER_BYTES: usize = 512 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: u32 = 5;
+/// Reconnection attempts in a row before giving up on a stream
+const MAX_RECONNECTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The title a station is playing right now, set once the decoder reads the
+/// audio that came with it
+pub type Title = Arc<Mutex<Option<String>>>;
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// Path and query, starting with `/`
+    path: String,
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err(format!("Not an http or https URL: {s}"));
+        };
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        // Drop the fragment, which never goes to the server
+        let path = path.split('#').next().unwrap_or("/").to_string();
+
+        let default_port = if tls { 443 } else { 80 };
+        // The colons in an IPv6 address come before the closing bracket
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse().map_err(|_| format!("Invalid port in {s}"))?;
+                (host, port)
+            }
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("No host in {s}"));
+        }
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+impl Url {
+    /// Resolves the target of a redirect, which may be relative to this URL
+    fn join(&self, location: &str) -> Result<Self, String> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return location.parse();
+        }
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let dir = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+            format!("{dir}{location}")
+        };
+        Ok(Self {
+            path,
+            ..self.clone()
+        })
+    }
+
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let default_port = if self.tls { 443 } else { 80 };
+        if self.port == default_port {
+            host
+        } else {
+            format!("{host}:{}", self.port)
+        }
+    }
+}
+
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    fn open(url: &Url) -> io::Result<Self> {
+        let mut last_error = None;
+        for addr in (url.host.as_str(), url.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(TIMEOUT))?;
+                    stream.set_write_timeout(Some(TIMEOUT))?;
+                    if !url.tls {
+                        return Ok(Self::Plain(stream));
+                    }
+                    let connector = TlsConnector::new().map_err(io::Error::other)?;
+                    let tls = connector
+                        .connect(&url.host, stream)
+                        .map_err(|e| io::Error::other(e.to_string()))?;
+                    return Ok(Self::Tls(Box::new(tls)));
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::other(format!("No address for {}", url.host))))
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A response whose headers were read, with the audio left to read
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Requests `url` from byte `offset` on, following redirects
+    fn get(url: &Url, offset: u64) -> io::Result<(Url, Self)> {
+        let mut url = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = Self::get_once(&url, offset)?;
+            match (response.status, response.header("location")) {
+                (301 | 302 | 303 | 307 | 308, Some(location)) => {
+                    url = url.join(location).map_err(io::Error::other)?;
+                    debug!("Redirected to {}{}", url.host, url.path);
+                }
+                (200..=299, _) => return Ok((url, response)),
+                (status, _) => {
+                    return Err(io::Error::other(format!("The server answered {status}")))
+                }
+            }
+        }
+        Err(io::Error::other("Too many redirects"))
+    }
+
+    fn get_once(url: &Url, offset: u64) -> io::Result<Self> {
+        let mut connection = Connection::open(url)?;
+        // HTTP/1.0 keeps servers from sending the body in chunks
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: pwplayer/{}\r\nIcy-MetaData: 1\r\n",
+            url.path,
+            url.host_header(),
+            env!("CARGO_PKG_VERSION"),
+        );
+        if offset > 0 {
+            request.push_str(&format!("Range: bytes={offset}-\r\n"));
+        }
+        request.push_str("\r\n");
+        connection.write_all(request.as_bytes())?;
+
+        let mut reader = BufReader::new(connection);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        // Old SHOUTcast servers answer with "ICY 200 OK"
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("Invalid response: {}", line.trim())))?;
+
+        let mut headers = vec![];
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                break;
+            };
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let metaint = headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case("icy-metaint"))
+            .and_then(|(_, value)| value.parse().ok())
+            .filter(|&metaint| metaint > 0);
+        Ok(Self {
+            status,
+            headers,
+            body: Body {
+                reader,
+                metaint,
+                until_meta: metaint.unwrap_or(0),
+                title: None,
+            },
+        })
+    }
+}
+
+/// The audio in a response, without the metadata blocks stations put into it
+struct Body {
+    reader: BufReader<Connection>,
+    /// Bytes of audio between metadata blocks
+    metaint: Option<usize>,
+    until_meta: usize,
+    /// The last title that came in
+    title: Option<String>,
+}
+
+impl Body {
+    fn read_audio(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(metaint) = self.metaint else {
+            return self.reader.read(buf);
+        };
+        if self.until_meta == 0 {
+            self.read_metadata()?;
+            self.until_meta = metaint;
+        }
+        let len = buf.len().min(self.until_meta);
+        let read = self.reader.read(&mut buf[..len])?;
+        self.until_meta -= read;
+        Ok(read)
+    }
+
+    /// Reads a metadata block, a length byte followed by 16 times as many bytes
+    /// of e.g. `StreamTitle='Artist - Song';`
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut len = [0];
+        self.reader.read_exact(&mut len)?;
+        if len[0] == 0 {
+            return Ok(());
+        }
+        let mut block = vec![0; len[0] as usize * 16];
+        self.reader.read_exact(&mut block)?;
+        if let Some(title) = stream_title(&String::from_utf8_lossy(&block)) {
+            self.title = Some(title);
+        }
+        Ok(())
+    }
+}
+
+fn is_icy(header: &str) -> bool {
+    header
+        .get(..4)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("icy-"))
+}
+
+fn stream_title(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = metadata[start..].trim_end_matches('\0');
+    let title = match rest.find("';") {
+        Some(end) => &rest[..end],
+        None => rest.strip_suffix('\'').unwrap_or(rest),
+    };
+    let title = title.trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    /// Bytes ever pushed, so the end of `data` is at this offset in the stream
+    pushed: u64,
+    /// Titles that came in with the offset their audio starts at, waiting for
+    /// the reader to get there
+    titles: VecDeque<(u64, String)>,
+    /// The download ended, either with the whole file or with `error`
+    finished: bool,
+    error: Option<io::Error>,
+    /// The reading side is gone, so the download should stop
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    buffer: Mutex<Buffer>,
+    changed: Condvar,
+}
+
+impl Shared {
+    /// Waits for room in the buffer. Returns false once nobody reads anymore.
+    /// A title starts with these bytes.
+    fn push(&self, bytes: &[u8], title: Option<String>) -> bool {
+        let mut buffer = self.buffer.lock().unwrap();
+        while buffer.data.len() >= BUFFER_BYTES && !buffer.closed {
+            buffer = self.changed.wait(buffer).unwrap();
+        }
+        if buffer.closed {
+            return false;
+        }
+        if let Some(title) = title {
+            let offset = buffer.pushed;
+            buffer.titles.push_back((offset, title));
+        }
+        buffer.data.extend(bytes);
+        buffer.pushed += bytes.len() as u64;
+        self.changed.notify_all();
+        true
+    }
+
+    /// Stops the download and any read waiting for it
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn finish(&self, error: Option<io::Error>) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.finished = true;
+        buffer.error = error;
+        self.changed.notify_all();
+    }
+}
+
+/// Closes a stream from another thread than the one reading it, so a read
+/// that waits for a stalled download gives up
+#[derive(Clone)]
+pub struct Closer(Arc<Shared>);
+
+impl Closer {
+    pub fn close(&self) {
+        self.0.close();
+    }
+}
+
+/// A song being downloaded, read as a source that can't seek
+pub struct HttpSource {
+    shared: Arc<Shared>,
+    /// Length of the whole file, which live streams don't have
+    length: Option<u64>,
+    content_type: Option<String>,
+    /// The station's name, for streams that have one
+    pub name: Option<String>,
+    pub title: Title,
+    extension: Option<String>,
+}
+
+impl HttpSource {
+    pub fn open(url: &str) -> io::Result<Self> {
+        let url: Url = url.parse().map_err(io::Error::other)?;
+        let (url, response) = Response::get(&url, 0)?;
+        info!("Streaming {}{}", url.host, url.path);
+
+        let length = response
+            .header("content-length")
+            .and_then(|l| l.parse().ok());
+        let content_type = response.header("content-type").map(str::to_string);
+        let name = response.header("icy-name").map(str::to_string);
+        // Stations send icy- headers, plain files don't
+        let live = length.is_none() && response.headers.iter().any(|(n, _)| is_icy(n));
+        let extension = Path::new(url.path.split('?').next().unwrap_or(""))
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_string);
+
+        let shared = Arc::new(Shared::default());
+        thread::spawn({
+            let shared = shared.clone();
+            move || download(url, response, length, live, shared)
+        });
+
+        Ok(Self {
+            shared,
+            length,
+            content_type,
+            name,
+            title: Title::default(),
+            extension,
+        })
+    }
+
+    /// What kind of file this probably is, to help probing streams that don't
+    /// have a file name
+    pub fn extension(&self) -> Option<&str> {
+        let from_type = match self.content_type.as_deref()?.split(';').next()?.trim() {
+            "audio/mpeg" | "audio/mp3" => Some("mp3"),
+            "audio/flac" | "audio/x-flac" => Some("flac"),
+            "audio/wav" | "audio/x-wav" | "audio/wave" => Some("wav"),
+            _ => None,
+        };
+        from_type.or(self.extension.as_deref())
+    }
+
+    pub fn closer(&self) -> Closer {
+        Closer(self.shared.clone())
+    }
+}
+
+/// Downloads into the buffer until the file ends or the stream can't be
+/// picked back up
+fn download(url: Url, response: Response, length: Option<u64>, live: bool, shared: Arc<Shared>) {
+    let mut body = Some(response.body);
+    let mut received = 0;
+    let mut attempts = 0;
+    let mut chunk = vec![0; 16 * 1024];
+
+    loop {
+        let error = match body.as_mut() {
+            Some(current) => match current.read_audio(&mut chunk) {
+                Ok(0) => None,
+                Ok(read) => {
+                    attempts = 0;
+                    received += read as u64;
+                    if !shared.push(&chunk[..read], current.title.take()) {
+                        debug!("Stopped streaming");
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Some(e),
+            },
+            None => None,
+        };
+
+        // Live streams never end on their own, and a cut off download goes on
+        // from where it stopped. Anything else can only be started over.
+        let complete = length.is_some_and(|length| received >= length);
+        if complete || (length.is_none() && !live) {
+            shared.finish(error);
+            return;
+        }
+
+        attempts += 1;
+        if attempts > MAX_RECONNECTS {
+            let error = error.unwrap_or_else(|| io::Error::other("The stream keeps dropping"));
+            shared.finish(Some(error));
+            return;
+        }
+        match &error {
+            Some(e) => warn!("Stream dropped, reconnecting: {e}"),
+            None => warn!("Stream ended, reconnecting"),
+        }
+        thread::sleep(RECONNECT_DELAY * attempts);
+        if shared.buffer.lock().unwrap().closed {
+            return;
+        }
+
+        let offset = if length.is_some() { received } else { 0 };
+        body = match Response::get(&url, offset) {
+            // A server that ignores the range would start over
+            Ok((_, response)) if offset > 0 && response.status != 206 => {
+                let e = io::Error::other("The server can't resume the download");
+                shared.finish(Some(e));
+                return;
+            }
+            Ok((_, response)) => Some(response.body),
+            Err(e) => {
+                warn!("Failed to reconnect: {e}");
+                None
+            }
+        };
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        while buffer.data.is_empty() && !buffer.finished && !buffer.closed {
+            buffer = self.shared.changed.wait(buffer).unwrap();
+        }
+        if buffer.closed {
+            return Err(io::Error::other("The stream was closed"));
+        }
+        if buffer.data.is_empty() {
+            return match buffer.error.take() {
+                Some(e) => Err(e),
+                None => Ok(0),
+            };
+        }
+
+        let (front, _) = buffer.data.as_slices();
+        let read = front.len().min(buf.len());
+        buf[..read].copy_from_slice(&front[..read]);
+        buffer.data.drain(..read);
+
+        // The title changes once the audio that came after it is being read
+        let position = buffer.pushed - buffer.data.len() as u64;
+        while buffer
+            .titles
+            .front()
+            .is_some_and(|(offset, _)| *offset < position)
+        {
+            let (_, title) = buffer.titles.pop_front().unwrap();
+            info!("Now playing {title}");
+            *self.title.lock().unwrap() = Some(title);
+        }
+        self.shared.changed.notify_all();
+        Ok(read)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Streams can't seek",
+        ))
+    }
+}
+
+impl MediaSource for HttpSource {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+impl Drop for HttpSource {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    /// Answers one connection after another with `responses`, sending the
+    /// requests it got back
+    fn serve(responses: Vec<Vec<u8>>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/radio.mp3", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                while reader.read_line(&mut request).unwrap() > 2 {}
+                sender.send(request).unwrap();
+                reader.get_mut().write_all(&response).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn metadata(text: &str) -> Vec<u8> {
+        let mut block = text.as_bytes().to_vec();
+        block.resize(text.len().div_ceil(16) * 16, 0);
+        let mut metadata = vec![(block.len() / 16) as u8];
+        metadata.extend(block);
+        metadata
+    }
+
+    #[test]
+    fn strips_metadata_and_changes_title_in_time() {
+        let mut response = b"ICY 200 OK\r\nicy-name: Test FM\r\nicy-metaint: 8\r\n\r\n".to_vec();
+        response.extend(b"abcdefgh");
+        response.extend(metadata("StreamTitle='Artist - Song';StreamUrl='';"));
+        response.extend(b"ijklmnop");
+        response.push(0);
+        response.extend(b"qrstuvwx");
+        let (url, requests) = serve(vec![response]);
+
+        let mut source = HttpSource::open(&url).unwrap();
+        assert!(requests.recv().unwrap().contains("Icy-MetaData: 1"));
+        assert_eq!(source.name.as_deref(), Some("Test FM"));
+
+        let mut audio = [0; 8];
+        source.read_exact(&mut audio).unwrap();
+        assert_eq!(&audio, b"abcdefgh");
+        // The title waits for its audio even once it's downloaded
+        while source.shared.buffer.lock().unwrap().pushed < 24 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*source.title.lock().unwrap(), None);
+
+        let mut audio = [0; 16];
+        source.read_exact(&mut audio).unwrap();
+        assert_eq!(&audio, b"ijklmnopqrstuvwx");
+        let title = source.title.lock().unwrap().clone();
+        assert_eq!(title.as_deref(), Some("Artist - Song"));
+    }
+
+    #[test]
+    fn resumes_dropped_download() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut first = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec();
+        first.extend(&data[..40]);
+        let mut rest = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 60\r\n\r\n".to_vec();
+        rest.extend(&data[40..]);
+        let (url, requests) = serve(vec![first, rest]);
+
+        let mut source = HttpSource::open(&url).unwrap();
+        let mut read = vec![];
+        source.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert!(!requests.recv().unwrap().contains("Range"));
+        assert!(requests.recv().unwrap().contains("Range: bytes=40-\r\n"));
+    }
+
+    #[test]
+    fn closing_stops_a_stalled_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/radio.mp3", listener.local_addr().unwrap());
+        // Answers and then sends nothing, with the connection kept open
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b"ICY 200 OK\r\nicy-name: Test FM\r\n\r\n")
+                .unwrap();
+            stream
+        });
+
+        let mut source = HttpSource::open(&url).unwrap();
+        let closer = source.closer();
+        let (sender, read) = mpsc::channel();
+        thread::spawn(move || sender.send(source.read(&mut [0; 8]).is_err()));
+        closer.close();
+        assert_eq!(read.recv_timeout(Duration::from_secs(5)), Ok(true));
+        drop(server.join());
+    }
+
+    #[test]
+    fn parses_urls() {
+        let url: Url = "https://example.com:8443/a/b.mp3?x=1#top".parse().unwrap();
+        assert_eq!(
+            url,
+            Url {
+                tls: true,
+                host: "example.com".to_string(),
+                port: 8443,
+                path: "/a/b.mp3?x=1".to_string(),
+            }
+        );
+
+        let url: Url = "http://example.com".parse().unwrap();
+        assert_eq!((url.port, url.path.as_str()), (80, "/"));
+        let url: Url = "http://example.com?x".parse().unwrap();
+        assert_eq!(url.path, "/?x");
+        let url: Url = "http://[::1]:8000/stream".parse().unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 8000));
+        assert_eq!(url.host_header(), "[::1]:8000");
+
+        for invalid in ["ftp://example.com", "http://", "http://example.com:port/"] {
+            assert!(invalid.parse::<Url>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn joins_redirects() {
+        let url: Url = "http://example.com/a/b.mp3".parse().unwrap();
+        let joined = |location| url.join(location).unwrap();
+        assert_eq!(joined("c.mp3").path, "/a/c.mp3");
+        assert_eq!(joined("/c.mp3").path, "/c.mp3");
+
+        let other = joined("https://other.org/c.mp3");
+        assert_eq!(
+            (other.tls, other.host.as_str(), other.port),
+            (true, "other.org", 443)
+        );
+    }
+
+    #[test]
+    fn finds_stream_titles() {
+        let title = stream_title;
+        assert_eq!(
+            title("StreamTitle='Artist - Song';StreamUrl='';").as_deref(),
+            Some("Artist - Song")
+        );
+        assert_eq!(
+            title("StreamTitle='Guns N' Roses - Song';\0\0").as_deref(),
+            Some("Guns N' Roses - Song")
+        );
+        assert_eq!(
+            title("StreamTitle='Cut off'\0\0").as_deref(),
+            Some("Cut off")
+        );
+        assert_eq!(title("StreamTitle='';"), None);
+        assert_eq!(title("StreamUrl='';"), None);
+    }
+}
@@ -0,0 +1,348 @@
+//! An index of the songs in a library and their tags, to pick songs by what
+//! they are instead of where they are.
+//!
+//! The index is a state file with one tab separated line per song, ending in
+//! the path. Rescans only read the tags of files modified since the last one.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{Display, Write},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+
+use crate::{
+    cue::{self, CueSheet},
+    error::PlayerError,
+    song::SongReader,
+    state,
+};
+
+const LIBRARY_FILE: &str = "library";
+
+/// The index as it was last loaded, with when its file was modified then
+static LOADED: Mutex<Option<(Option<SystemTime>, Arc<Library>)>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// When the file was last modified, in nanoseconds since the epoch
+    mtime: u64,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+}
+
+impl Entry {
+    fn read(path: &Path, mtime: u64) -> Result<Self, PlayerError> {
+        let song = SongReader::open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            mtime,
+            title: song.name,
+            artist: song.artist,
+            album: song.album,
+            track: song.track,
+        })
+    }
+
+    fn matches(&self, term: &Term) -> bool {
+        let fields = match term.field {
+            Some(Field::Title) => vec![self.title.as_deref()],
+            Some(Field::Artist) => vec![self.artist.as_deref()],
+            Some(Field::Album) => vec![self.album.as_deref()],
+            Some(Field::Path) => vec![self.path.to_str()],
+            None => vec![
+                self.title.as_deref(),
+                self.artist.as_deref(),
+                self.album.as_deref(),
+                self.path.to_str(),
+            ],
+        };
+        fields
+            .into_iter()
+            .flatten()
+            .any(|field| field.to_lowercase().contains(&term.value))
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let field = |value: &Option<String>| value.clone().unwrap_or_default();
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            field(&self.artist),
+            field(&self.album),
+            field(&self.title),
+            self.path.display()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Library {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Library {
+    /// The index, only read again once the file changes. That still reads
+    /// the disk, so it's kept off the PipeWire loop.
+    pub fn shared() -> Arc<Self> {
+        let modified = state::modified(LIBRARY_FILE);
+        let mut loaded = LOADED.lock().unwrap();
+        match loaded.as_ref() {
+            Some((at, library)) if *at == modified => library.clone(),
+            _ => {
+                let library = Arc::new(Self::load());
+                *loaded = Some((modified, library.clone()));
+                library
+            }
+        }
+    }
+
+    fn load() -> Self {
+        let entries = state::read(LIBRARY_FILE)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.splitn(6, '\t').collect();
+                let [mtime, track, title, artist, album, path] = fields[..] else {
+                    return None;
+                };
+                let tag = |value: &str| (!value.is_empty()).then(|| value.to_string());
+                let entry = Entry {
+                    path: path.into(),
+                    mtime: mtime.parse().ok()?,
+                    title: tag(title),
+                    artist: tag(artist),
+                    album: tag(album),
+                    track: track.parse().ok(),
+                };
+                Some((entry.path.clone(), entry))
+            })
+            .collect();
+        Self { entries }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        // Tabs and line breaks in tags would break up the line
+        let clean = |value: &Option<String>| {
+            value
+                .as_deref()
+                .unwrap_or_default()
+                .replace(['\t', '\n', '\r'], " ")
+        };
+        let mut saved = String::new();
+        for e in self.entries.values() {
+            let track = e.track.map(|t| t.to_string()).unwrap_or_default();
+            let _ = writeln!(
+                saved,
+                "{}\t{track}\t{}\t{}\t{}\t{}",
+                e.mtime,
+                clean(&e.title),
+                clean(&e.artist),
+                clean(&e.album),
+                e.path.display()
+            );
+        }
+        state::write(LIBRARY_FILE, &saved)
+    }
+
+    /// Songs that match every term, by artist, album and track
+    pub fn search(&self, query: &Query) -> Vec<&Entry> {
+        let mut found: Vec<&Entry> = self
+            .entries
+            .values()
+            .filter(|e| query.terms.iter().all(|term| e.matches(term)))
+            .collect();
+        found.sort_by(|a, b| {
+            (&a.artist, &a.album, a.track, &a.path).cmp(&(&b.artist, &b.album, b.track, &b.path))
+        });
+        found
+    }
+}
+
+/// Nanoseconds since the epoch `path` was last modified at. Tracks of a CUE
+/// sheet go by both the sheet and the audio file, added up so a change to
+/// either shows.
+fn mtime(path: &Path) -> io::Result<u64> {
+    let modified = |path: &Path| -> io::Result<u64> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        Ok(modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64))
+    };
+
+    let Some((sheet, number)) = cue::split(path) else {
+        return modified(path);
+    };
+    let audio = match CueSheet::read(&sheet)?.track(number) {
+        Some(track) => modified(&track.file)?,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "No such track")),
+    };
+    Ok(modified(&sheet)?.wrapping_add(audio))
+}
+
+/// Reads the tags of every song under `root` into the index. Songs that
+/// weren't modified since they were last indexed are skipped, and songs under
+/// `root` that are gone are dropped.
+pub fn index<T: AsRef<Path>>(root: T) -> Result<(), Box<dyn Error>> {
+    // Stored absolute, so the player finds them from any directory
+    let root = std::fs::canonicalize(root)?;
+    let library = Library::load();
+    let (mut previous, entries): (BTreeMap<_, _>, BTreeMap<_, _>) = library
+        .entries
+        .into_iter()
+        .partition(|(path, _)| path.starts_with(&root));
+    let mut library = Library { entries };
+
+    let (mut read, mut unchanged) = (0, 0);
+    for path in crate::handle_input_path(&root)? {
+        let Ok(mtime) = mtime(&path) else {
+            continue;
+        };
+        let entry = match previous.remove(&path).filter(|e| e.mtime == mtime) {
+            Some(entry) => {
+                unchanged += 1;
+                entry
+            }
+            None => match Entry::read(&path, mtime) {
+                Ok(entry) => {
+                    read += 1;
+                    entry
+                }
+                Err(e) => {
+                    debug!("Not indexing {}: {e}", path.display());
+                    continue;
+                }
+            },
+        };
+        library.entries.insert(path, entry);
+    }
+
+    library.save()?;
+    println!(
+        "Indexed {} songs under {}: {read} read, {unchanged} unchanged, {} removed",
+        read + unchanged,
+        root.display(),
+        previous.len()
+    );
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    Path,
+}
+
+#[derive(Debug, Clone)]
+struct Term {
+    /// Any field when None
+    field: Option<Field>,
+    /// Lowercase, since matching ignores case
+    value: String,
+}
+
+/// Terms that all have to be found in a song, e.g.
+/// `artist:"pink floyd" album:wall`. Words without a field match any of them.
+#[derive(Debug, Clone)]
+pub struct Query {
+    terms: Vec<Term>,
+    text: String,
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on whitespace outside of quotes
+        let mut words = vec![];
+        let mut word = String::new();
+        let mut quoted = false;
+        for c in s.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                c if c.is_whitespace() && !quoted => words.push(std::mem::take(&mut word)),
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+
+        let terms: Vec<Term> = words
+            .into_iter()
+            .filter(|w| !w.is_empty())
+            .map(|word| {
+                let (field, value) = match word.split_once(':') {
+                    Some(("title", value)) => (Some(Field::Title), value),
+                    Some(("artist", value)) => (Some(Field::Artist), value),
+                    Some(("album", value)) => (Some(Field::Album), value),
+                    Some(("path", value)) => (Some(Field::Path), value),
+                    _ => (None, word.as_str()),
+                };
+                Term {
+                    field,
+                    value: value.to_lowercase(),
+                }
+            })
+            .collect();
+
+        if terms.is_empty() {
+            return Err("Expected a query, e.g. artist:name album:title".to_string());
+        }
+        Ok(Self {
+            terms,
+            text: s.trim().to_string(),
+        })
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::Duration};
+
+    use super::*;
+    use crate::song::test_song;
+
+    #[test]
+    fn cue_tracks_change_with_their_audio() {
+        let audio = test_song("library/album.wav", 44100, 100);
+        let sheet = audio.with_extension("cue");
+        let cue = "FILE \"album.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n";
+        std::fs::write(&sheet, cue).unwrap();
+        let track = cue::track_path(&sheet, 1);
+
+        let set_modified = |path: &Path, time: SystemTime| {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        set_modified(&sheet, time);
+        set_modified(&audio, time);
+        let before = mtime(&track).unwrap();
+
+        // Less than a second later, with only the audio replaced
+        set_modified(&audio, time + Duration::from_millis(1));
+        assert_ne!(mtime(&track).unwrap(), before);
+    }
+}
@@ -0,0 +1,311 @@
+//! Loudness measurement following EBU R128 / ITU-R BS.1770.
+//!
+//! Measures integrated loudness (with absolute and relative gating) and true
+//! peak (via 4x oversampling) of interleaved f32 samples.
+
+use std::f64::consts::PI;
+
+use crate::dsp::biquad::Biquad;
+
+/// Gating blocks are 400ms long and start every 100ms
+const SUBBLOCKS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// The K-weighting pre-filter: a high shelf followed by a high pass
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let shelf = Biquad::new(
+        [
+            vh + vb * k / q + k * k,
+            2.0 * (k * k - vh),
+            vh - vb * k / q + k * k,
+        ],
+        [
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [a0, -2.0 * a0, a0],
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+
+    [shelf, high_pass]
+}
+
+/// Interpolation filter for true peak detection, split into one set of taps
+/// per output phase.
+fn true_peak_filter() -> [[f64; TAPS_PER_PHASE]; OVERSAMPLING] {
+    let len = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (len - 1) as f64 / 2.0;
+    let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+
+    for i in 0..len {
+        let t = (i as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+        // Blackman window
+        let w = 2.0 * PI * i as f64 / (len - 1) as f64;
+        let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+        phases[i % OVERSAMPLING][i / OVERSAMPLING] = sinc * window;
+    }
+
+    phases
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Integrated loudness of a set of 400ms block energies, in LUFS
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let absolute: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|e| energy_to_loudness(*e) > ABSOLUTE_GATE)
+        .collect();
+    if absolute.is_empty() {
+        return None;
+    }
+
+    let mean = absolute.iter().sum::<f64>() / absolute.len() as f64;
+    let threshold = energy_to_loudness(mean) + RELATIVE_GATE;
+    let relative: Vec<f64> = absolute
+        .into_iter()
+        .filter(|e| energy_to_loudness(*e) > threshold)
+        .collect();
+    if relative.is_empty() {
+        return None;
+    }
+
+    let mean = relative.iter().sum::<f64>() / relative.len() as f64;
+    Some(energy_to_loudness(mean))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// True peak as a linear sample value
+    pub peak: f64,
+}
+
+/// Measures the loudness of one track. The measured blocks can be kept to
+/// compute the loudness of a whole album afterwards.
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    /// Samples per channel in one 100ms subblock
+    subblock_len: usize,
+    subblock_pos: usize,
+    /// Running sum of squares in the current subblock
+    subblock_sum: f64,
+    /// Sums of the last few subblocks
+    recent: Vec<f64>,
+    blocks: Vec<f64>,
+    peak_filter: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    /// Most recent input samples per channel, newest first
+    history: Vec<[f64; TAPS_PER_PHASE]>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        Self {
+            channels,
+            filters: vec![k_weighting(rate); channels],
+            subblock_len: (rate as usize / 10).max(1),
+            subblock_pos: 0,
+            subblock_sum: 0.0,
+            recent: Vec::with_capacity(SUBBLOCKS_PER_BLOCK),
+            blocks: vec![],
+            peak_filter: true_peak_filter(),
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            peak: 0.0,
+        }
+    }
+
+    /// Feeds interleaved samples into the meter
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let x = *sample as f64;
+                self.track_peak(channel, x);
+
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let y = high_pass.process(shelf.process(x));
+                self.subblock_sum += y * y;
+            }
+
+            self.subblock_pos += 1;
+            if self.subblock_pos == self.subblock_len {
+                self.finish_subblock();
+            }
+        }
+    }
+
+    fn track_peak(&mut self, channel: usize, x: f64) {
+        let history = &mut self.history[channel];
+        history.copy_within(..TAPS_PER_PHASE - 1, 1);
+        history[0] = x;
+
+        self.peak = self.peak.max(x.abs());
+        for phase in &self.peak_filter {
+            let y: f64 = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+
+    fn finish_subblock(&mut self) {
+        if self.recent.len() == SUBBLOCKS_PER_BLOCK {
+            self.recent.remove(0);
+        }
+        self.recent.push(self.subblock_sum);
+        self.subblock_sum = 0.0;
+        self.subblock_pos = 0;
+
+        if self.recent.len() == SUBBLOCKS_PER_BLOCK {
+            let samples = (self.subblock_len * SUBBLOCKS_PER_BLOCK) as f64;
+            self.blocks.push(self.recent.iter().sum::<f64>() / samples);
+        }
+    }
+
+    /// Loudness of everything processed so far. None if it was too quiet to
+    /// measure.
+    pub fn loudness(&self) -> Option<Loudness> {
+        gated_loudness(&self.blocks).map(|integrated| Loudness {
+            integrated,
+            peak: self.peak,
+        })
+    }
+
+    /// Loudness of several tracks played back to back
+    pub fn combined(meters: &[&LoudnessMeter]) -> Option<Loudness> {
+        let blocks: Vec<f64> = meters
+            .iter()
+            .flat_map(|m| m.blocks.iter().copied())
+            .collect();
+        let peak = meters.iter().map(|m| m.peak).fold(0.0, f64::max);
+        gated_loudness(&blocks).map(|integrated| Loudness { integrated, peak })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// A stereo sine at `dbfs` in the channels `on` says, starting at `phase`
+    fn sine(freq: f64, dbfs: f64, seconds: f64, on: [bool; 2], phase: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        let frames = (seconds * RATE as f64) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / RATE as f64;
+                let x = (amplitude * (2.0 * PI * freq * t + phase).sin()) as f32;
+                on.map(|on| if on { x } else { 0.0 })
+            })
+            .collect()
+    }
+
+    fn measure(samples: &[f32]) -> LoudnessMeter {
+        let mut meter = LoudnessMeter::new(RATE, 2);
+        meter.process(samples);
+        meter
+    }
+
+    fn integrated(samples: &[f32]) -> Option<f64> {
+        measure(samples).loudness().map(|l| l.integrated)
+    }
+
+    #[test]
+    fn measures_sines() {
+        // -20 dBFS in one channel is the usual calibration tone
+        let one = integrated(&sine(997.0, -20.0, 5.0, [true, false], 0.0)).unwrap();
+        assert!((one + 23.0).abs() < 0.05, "{one}");
+        // EBU Tech 3341 case 1, and the same tone 3 dB louder
+        let both = integrated(&sine(1000.0, -23.0, 20.0, [true, true], 0.0)).unwrap();
+        assert!((both + 23.0).abs() < 0.05, "{both}");
+        let both = integrated(&sine(997.0, -20.0, 5.0, [true, true], 0.0)).unwrap();
+        assert!((both + 20.0).abs() < 0.05, "{both}");
+    }
+
+    #[test]
+    fn blocks_overlap() {
+        // 400 ms blocks every 100 ms
+        let meter = measure(&sine(997.0, -20.0, 2.0, [true, true], 0.0));
+        assert_eq!(meter.blocks.len(), 17);
+        // Too short for a single block
+        assert_eq!(
+            integrated(&sine(997.0, -20.0, 0.3, [true, true], 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn gates_quiet_parts() {
+        assert_eq!(
+            integrated(&sine(997.0, -80.0, 5.0, [true, true], 0.0)),
+            None
+        );
+        assert_eq!(integrated(&vec![0.0; RATE as usize * 2]), None);
+
+        // The quiet half is more than 10 LU below the rest
+        let mut samples = sine(997.0, -20.0, 10.0, [true, true], 0.0);
+        samples.extend(sine(997.0, -40.0, 10.0, [true, true], 0.0));
+        let loudness = integrated(&samples).unwrap();
+        assert!((loudness + 20.0).abs() < 0.1, "{loudness}");
+    }
+
+    #[test]
+    fn finds_peaks_between_samples() {
+        // A quarter of the rate with every sample 45° off the crest
+        let samples = sine(12000.0, -6.0, 1.0, [true, true], PI / 4.0);
+        let sample_peak = samples.iter().fold(0.0, |p: f32, s| p.max(s.abs())) as f64;
+        let true_peak = 10f64.powf(-6.0 / 20.0);
+        assert!((sample_peak - true_peak * 0.5f64.sqrt()).abs() < 1e-3);
+
+        let peak = measure(&samples).loudness().unwrap().peak;
+        assert!(peak > sample_peak * 1.3, "{peak}");
+        assert!((peak - true_peak).abs() < 0.03, "{peak}");
+    }
+
+    #[test]
+    fn combines_albums() {
+        let loud = measure(&sine(997.0, -20.0, 10.0, [true, true], 0.0));
+        let quiet = measure(&sine(997.0, -30.0, 10.0, [true, true], 0.0));
+        let silent = measure(&vec![0.0; RATE as usize * 20]);
+
+        // The mean of the energies, not of the loudnesses
+        let album = LoudnessMeter::combined(&[&loud, &quiet]).unwrap();
+        let expected = 10.0 * ((10f64.powf(-2.0) + 10f64.powf(-3.0)) / 2.0).log10();
+        assert!((album.integrated - expected).abs() < 0.1, "{album:?}");
+        assert_eq!(album.peak, loud.peak);
+
+        // Silence is gated out rather than pulling the album down
+        let album = LoudnessMeter::combined(&[&loud, &silent]).unwrap();
+        assert!((album.integrated - loud.loudness().unwrap().integrated).abs() < 1e-9);
+        assert_eq!(LoudnessMeter::combined(&[&silent]), None);
+    }
+}
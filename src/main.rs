@@ -1,16 +1,49 @@
 use std::{
-    error::Error,
+    collections::HashSet,
+    io,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
+use cli::{Args, Mode, Options};
+use cue::CueSheet;
+use decoder::OnDecodeError;
+use error::PlayerError;
 use log::{info, warn};
+use pipewire::main_loop::MainLoop;
 use pw::PipewireClient;
-use rand::seq::SliceRandom;
+use queue::Queue;
+use session::Session;
 use song::SongReader;
+use status::Status;
 
+mod chapter;
+mod check;
+mod cli;
 mod command;
+mod cue;
+mod decoder;
+mod dsp;
+mod error;
+mod events;
+mod fade;
+mod flac;
+mod http;
+mod library;
+mod loudness;
 mod pw;
+mod queue;
+mod render;
+mod replaygain;
+mod resume;
+mod ring;
+mod scan;
+mod session;
+mod shutdown;
 mod song;
+mod state;
+mod status;
+mod volume;
 
 fn init_logger() {
     if std::env::var("RUST_LOG").is_err() {
@@ -27,32 +60,58 @@ fn init_logger() {
     pretty_env_logger::init();
 }
 
-fn walk_dir_recursive<T: AsRef<Path>>(
-    dir: T,
-    data: &mut Vec<PathBuf>,
-) -> Result<(), Box<dyn Error>> {
-    let dir = std::fs::read_dir(dir)?;
-    for file in dir.flatten() {
-        let path = file.path();
+fn walk_dir_recursive<T: AsRef<Path>>(dir: T, data: &mut Vec<PathBuf>) -> io::Result<()> {
+    // Sorted, since read_dir lists them in whatever order the filesystem has
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+
+    let mut files = vec![];
+    for path in entries {
         if path.is_dir() {
             walk_dir_recursive(path, data)?;
         } else if path.is_file() {
-            data.push(path);
+            files.push(path);
+        }
+    }
+
+    // Files that a CUE sheet splits up are played as its tracks instead
+    let mut split = HashSet::new();
+    for path in files.iter().filter(|path| cue::is_cue(path)) {
+        match CueSheet::read(path) {
+            Ok(sheet) => {
+                split.extend(sheet.files().map(Path::to_owned));
+                data.extend(sheet.entries(path));
+            }
+            Err(e) => warn!("Ignoring CUE sheet: {e}"),
         }
     }
+    data.extend(
+        files
+            .into_iter()
+            .filter(|path| !cue::is_cue(path) && !split.contains(path)),
+    );
     Ok(())
 }
 
-fn walk_dir<T: AsRef<Path>>(dir: T) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+fn walk_dir<T: AsRef<Path>>(dir: T) -> io::Result<Vec<PathBuf>> {
     let mut files = vec![];
     walk_dir_recursive(dir, &mut files)?;
     Ok(files)
 }
 
-fn handle_input_path<T: AsRef<Path>>(path: T) -> Result<Vec<PathBuf>, Box<dyn Error>> {
-    let md = std::fs::metadata(&path)?;
-    if md.is_file() {
-        Ok(vec![path.as_ref().to_owned()])
+fn handle_input_path<T: AsRef<Path>>(path: T) -> io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if path == Path::new(song::STDIN) || http::is_url(path) || cue::split(path).is_some() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let md = std::fs::metadata(path)?;
+    if md.is_file() && cue::is_cue(path) {
+        Ok(CueSheet::read(path)?.entries(path))
+    } else if md.is_file() {
+        Ok(vec![path.to_owned()])
     } else {
         walk_dir(path)
     }
@@ -60,45 +119,125 @@ fn handle_input_path<T: AsRef<Path>>(path: T) -> Result<Vec<PathBuf>, Box<dyn Er
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     init_logger();
+    let args = Args::parse()?;
+
+    let result = match args.mode {
+        Mode::Play(path) => play(path, args.options),
+        Mode::Scan(path) => scan::scan(path),
+        Mode::Check(path) => check::check(path),
+        Mode::Index(path) => library::index(path),
+        Mode::Render { inputs, output } => {
+            render::render(&inputs, Path::new(&output), &args.options)
+        }
+    };
+    shutdown::run();
+    result
+}
+
+fn play(path: Option<String>, options: Options) -> Result<(), Box<dyn std::error::Error>> {
     pipewire::init();
-    // TODO: better cli
-    let dir = std::env::args().skip(1).next().expect("Invalid args");
-    let mut files = handle_input_path(dir)?;
-    // Shuffle for fun
-    files.shuffle(&mut rand::thread_rng());
 
-    let tx = command::start_command_thread();
-    let mut client = PipewireClient::create(tx)?;
+    let session = options.resume.then(Session::load).flatten();
+    let (queue, start) = match (session, path) {
+        (Some(session), _) => session.into_queue(),
+        (None, None) if !options.daemon => return Err("No session to resume".into()),
+        (None, path) => {
+            // A daemon may start out with nothing to play
+            let entries = match path {
+                Some(path) => handle_input_path(path)?,
+                None => vec![],
+            };
+            let mut queue = Queue::new(entries);
+            queue.set_shuffle(options.shuffle);
+            queue.set_repeat(options.repeat);
+            (queue, None)
+        }
+    };
 
-    for file in files {
-        let file_pretty = file.display().to_string();
-        let song = match SongReader::from_file(&file) {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("Failed to load {file_pretty}: {e:?}");
-                continue;
+    let daemon = options.daemon;
+    let on_decode_error = options.on_decode_error;
+    let status = Arc::new(Status::default());
+    let queue = Arc::new(Mutex::new(queue));
+    let (tx, rx) = command::channel();
+    let mainloop = MainLoop::new(None)?;
+    // Before any other thread starts, so that they all leave these signals
+    // to the loop
+    let _signals = pw::quit_on_signals(&mainloop, &tx);
+    let client = PipewireClient::create(mainloop.clone(), status.clone(), queue.clone(), options)?;
+    // Commands are handled for the whole session, between songs too
+    let _commands = client.attach_commands(rx);
+    command::start_command_thread(tx, status.clone());
+    if let Some((path, seconds)) = start {
+        client.start_at(path, seconds);
+    }
+
+    loop {
+        let next = queue.lock().unwrap().pop();
+        let Some(file) = next else {
+            if !daemon {
+                break;
             }
+            session::save_finished(&queue);
+            client.idle();
+            if client.is_quitting() {
+                return Ok(());
+            }
+            continue;
         };
 
+        let file_pretty = file.display().to_string();
+        // A crossfade may have already started the next song
+        let song = match client.take_preloaded(&file) {
+            Some(song) => Ok(song),
+            None => SongReader::open(&file),
+        };
         // TODO: Support tracks with other channel counts
-        if song.channels != 2 {
-            warn!("Only 2 channel tracks are supported");
-            continue;
-        }
+        let song = song.and_then(|song| match song.channels {
+            2 => Ok(song),
+            _ => Err(PlayerError::UnsupportedFormat(
+                "Only 2 channel tracks are supported".into(),
+            )),
+        });
 
-        info!(
-            "Loaded {} | {} channels, {} Hz",
-            if song.name.is_some() {
-                song.name.as_ref().unwrap()
-            } else {
-                &file_pretty
-            },
-            song.channels,
-            song.rate
-        );
+        let played = song.and_then(|song| {
+            info!(
+                "Loaded {} | {} channels, {} Hz",
+                if song.name.is_some() {
+                    song.name.as_ref().unwrap()
+                } else {
+                    &file_pretty
+                },
+                song.channels,
+                song.rate
+            );
+            client.play_song(&file, song)
+        });
+        if let Err(e) = played {
+            events::song_error(&file, &e);
+            if on_decode_error == OnDecodeError::Stop {
+                status.stop_queue();
+            }
+        }
+        if client.is_quitting() {
+            // The session was saved where the song was left off
+            return Ok(());
+        }
 
-        let _ = client.play_song(song);
+        if status.take_queue_stopped() {
+            info!("Stopped after a song that couldn't be decoded");
+            if !daemon {
+                return Ok(());
+            }
+            // `play` goes on with the rest of the queue
+            client.idle();
+            if client.is_quitting() {
+                return Ok(());
+            }
+        }
     }
 
+    // Everything was played, so a resumed session starts over
+    session::save_finished(&queue);
+
     Ok(())
 }
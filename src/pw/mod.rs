@@ -1,51 +1,210 @@
-use std::{error::Error, io::ErrorKind, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 mod audio_info;
+mod sinks;
 mod stream;
+mod transport;
 
-use async_std::task;
-use futures::SinkExt;
-use log::{debug, error, warn};
-use pipewire::{channel, context::Context, core::Core, main_loop::MainLoop};
-use stream::{Stream, StreamMetadata};
+use log::{debug, info, warn};
+use pipewire::{
+    channel::AttachedReceiver,
+    context::Context,
+    core::Core,
+    loop_::{Signal, SignalSource},
+    main_loop::MainLoop,
+    stream::StreamState,
+};
+use sinks::{SinkEvent, SinkMonitor};
+use stream::{set_stream_volume, Stream, StreamMetadata};
+use symphonia::core::units::Time;
+use transport::{Transport, FADE_POLL_INTERVAL};
 
 use crate::{
-    command::{Command, Sender},
-    song::{SongReader, SongReaderError},
+    chapter,
+    cli::Options,
+    command::{Command, Receiver, Reply, Request, Sender},
+    decoder::{self, DecoderSettings, DecoderWorker, Handoff, NextSong, Preloaded},
+    error::PlayerError,
+    events,
+    fade::{FadeControl, FadeRamp},
+    library::Query,
+    queue::SharedQueue,
+    resume::{self, ResumeStore},
+    ring, session,
+    song::{unseekable, SongReader},
+    status::{SharedStatus, Status},
+    volume::{Volume, VolumeChange, VolumeCurve},
 };
 
-pub type PipewireLoopTx = channel::Sender<Command>;
+/// Seconds of decoded audio buffered ahead of the realtime thread
+const BUFFER_SECONDS: usize = 2;
+/// How often the position of a long song is saved while it plays
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the chapter is checked for changes to tell subscribers about
+const CHAPTER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the session is saved while playing
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
 
 // TODO: Handle this better
 pub struct PipewireClient {
     mainloop: MainLoop,
     _context: Context,
-    loop_rx: Option<channel::Receiver<Command>>,
-    command_tx: Sender<Command>,
+    dispatch: Rc<Dispatch>,
     core: Core,
+    sinks: Rc<SinkMonitor>,
+    status: SharedStatus,
+    settings: Rc<RefCell<DecoderSettings>>,
+    next: NextSong,
+    fade: Arc<FadeControl>,
+    /// Kept here so every new stream starts at the last volume
+    volume: Rc<Cell<Volume>>,
+    resume: Rc<RefCell<ResumeStore>>,
+    /// Where to start the next song at, set by `goto`
+    start_at: Rc<Cell<Option<(PathBuf, f64)>>>,
+    /// Set by `quit`, which stops the song without moving on to the next
+    quitting: Rc<Cell<bool>>,
+    options: Options,
+}
+
+/// Sends `quit` when the player is asked to stop by a signal. This blocks the
+/// signals on the calling thread, so it has to be called before any other
+/// thread is spawned for them to inherit that.
+pub fn quit_on_signals<'l>(
+    mainloop: &'l MainLoop,
+    command_tx: &Sender<Request>,
+) -> Vec<SignalSource<'l>> {
+    [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP]
+        .into_iter()
+        .map(|signal| {
+            let command_tx = command_tx.clone();
+            mainloop.loop_().add_signal_local(signal, move || {
+                info!("Received {signal:?}, quitting");
+                let _ = command_tx.send(Request::without_reply(Command::Quit));
+            })
+        })
+        .collect()
 }
 
 impl PipewireClient {
-    pub fn create(mut command_tx: Sender<Command>) -> Result<Self, Box<dyn Error>> {
-        let mainloop = MainLoop::new(None)?;
+    pub fn create(
+        mainloop: MainLoop,
+        status: SharedStatus,
+        queue: SharedQueue,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>> {
         let context = Context::new(&mainloop)?;
         let core = context.connect(None)?;
+        let sinks = Rc::new(SinkMonitor::new(&core)?);
 
-        let (loop_tx, loop_rx) = channel::channel();
-        task::block_on(command_tx.send(Command::UpdatePwSender(loop_tx)))?;
+        let volume = Volume::load();
+        status.set_volume(volume);
 
         let client = Self {
             mainloop,
             _context: context,
             core,
-            loop_rx: Some(loop_rx),
-            command_tx,
+            dispatch: Rc::default(),
+            sinks,
+            status,
+            settings: Rc::new(RefCell::new(DecoderSettings::new(&options))),
+            next: NextSong {
+                queue,
+                handoff: Handoff::default(),
+            },
+            fade: Arc::new(FadeControl::new(options.fade_ms)),
+            volume: Rc::new(Cell::new(volume)),
+            resume: Rc::new(RefCell::new(ResumeStore::load())),
+            start_at: Rc::default(),
+            quitting: Rc::default(),
+            options,
         };
 
         Ok(client)
     }
 
-    pub fn play_song(&mut self, mut song: SongReader) -> Result<(), pipewire::Error> {
+    /// Hands the commands from clients to whatever runs the loop, for as long
+    /// as the returned receiver is kept
+    pub fn attach_commands(&self, commands: Receiver<Request>) -> AttachedReceiver<'_, Request> {
+        let dispatch = self.dispatch.clone();
+        commands.attach(self.mainloop.loop_(), move |request| {
+            dispatch.dispatch(request)
+        })
+    }
+
+    /// Starts the song at `path` at a position once it comes up
+    pub fn start_at(&self, path: PathBuf, seconds: f64) {
+        self.start_at.set(Some((path, seconds)));
+    }
+
+    /// Whether `quit` stopped the last song
+    pub fn is_quitting(&self) -> bool {
+        self.quitting.get()
+    }
+
+    /// Takes the song a crossfade already started, if it is the one at `path`
+    pub fn take_preloaded(&self, path: &Path) -> Option<SongReader> {
+        let preloaded = self.next.handoff.lock().unwrap().take();
+        preloaded
+            .filter(|p| p.path == path)
+            .map(|Preloaded { song, .. }| song)
+    }
+
+    fn settings_handler(&self) -> SettingsHandler {
+        SettingsHandler {
+            decoder: self.settings.clone(),
+            fade: self.fade.clone(),
+            volume: self.volume.clone(),
+            curve: self.options.volume_curve,
+            status: self.status.clone(),
+            queue: self.next.queue.clone(),
+        }
+    }
+
+    /// Seeks a song that is just starting to where `goto` asked for, or to
+    /// where it was left off
+    fn resume_position(&self, path: &Path, hash: Option<u64>, song: &mut SongReader) {
+        let goto = self.start_at.take().filter(|(p, _)| p == path);
+        // A crossfade already started this song
+        if song.position() != 0 || !song.is_seekable() {
+            return;
+        }
+
+        let saved = hash.and_then(|hash| self.resume.borrow().position(path, hash));
+        if let Some(seconds) = goto.map(|(_, seconds)| seconds).or(saved) {
+            info!("Resuming {} at {seconds:.0}s", path.display());
+            if let Err(e) = song.seek_time(Time::from(seconds)) {
+                warn!("Failed to resume: {e:?}");
+            }
+        }
+    }
+
+    pub fn play_song(&self, path: &Path, mut song: SongReader) -> Result<(), PlayerError> {
+        // Only long songs remember their position
+        let length = song.length.map(|frames| frames as f64 / song.rate as f64);
+        // Songs from a pipe can neither be hashed nor resumed
+        let hash = if !song.is_seekable() {
+            None
+        } else if length.is_some_and(|l| l >= self.options.resume_threshold) {
+            resume::content_hash(path)
+                .map_err(|e| warn!("Failed to hash {}: {e:?}", path.display()))
+                .ok()
+        } else {
+            None
+        };
+        self.resume_position(path, hash, &mut song);
+
         let mut stream = Stream::new(
             &self.core,
             StreamMetadata {
@@ -60,71 +219,459 @@ impl PipewireClient {
             let _ = stream.set_name("pwplayer");
         }
 
+        let speed = self.settings.borrow().speed;
+        self.status
+            .start_song(song.name.clone(), song.rate, song.position(), speed);
+
+        let rate = song.rate;
+        let channels = song.channels as usize;
+        let seekable = song.is_seekable();
+        let chapters = song.chapters.clone();
+        self.status.set_chapters(chapters.clone());
+        let samples_per_second = rate as usize * channels;
+        let (producer, mut consumer) = ring::ring(samples_per_second * BUFFER_SECONDS);
+        let decoder = DecoderWorker::spawn(
+            path.to_owned(),
+            song,
+            producer,
+            self.status.clone(),
+            self.settings.borrow().clone(),
+            self.next.clone(),
+        );
+        // Give the decoder a head start so the first cycles don't underrun
+        decoder::prefill(&consumer, samples_per_second / 4);
+
+        self.fade.fade_in();
+        let mut ramp = FadeRamp::new(self.fade.clone(), rate);
+
+        let finished = Arc::new(AtomicBool::new(false));
         stream.set_process_callback({
             let mainloop = self.mainloop.clone();
+            let status = self.status.clone();
+            let finished = finished.clone();
             move |buffer| {
-                let chunk = match song.next_chunk() {
-                    Ok(chunk) => chunk,
-                    Err(SongReaderError::DecodeError(e)) => {
-                        warn!("Decoding error (not fatal): {e:?}");
-                        return 0;
-                    }
-                    Err(SongReaderError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                if consumer.take_clear() {
+                    status.finish_seek();
+                }
+
+                let read = consumer.pop(buffer);
+                if consumer.is_finished() {
+                    if read == 0 {
                         debug!("Song finished");
+                        finished.store(true, Ordering::Relaxed);
                         mainloop.quit();
-                        return 0;
-                    }
-                    Err(e) => {
-                        error!("Fatal error playing song: {e:?}");
-                        mainloop.quit();
-                        return 0;
                     }
-                };
+                } else if read < buffer.len() {
+                    status.underrun();
+                }
+                ramp.apply(&mut buffer[..read], channels);
+                ramp.advance((buffer.len() - read) / channels);
 
-                let samples_to_write = buffer.len().min(chunk.len());
-                buffer[..samples_to_write].copy_from_slice(&chunk.samples()[..samples_to_write]);
+                let frames = read / channels;
+                status.advance(frames as u64);
                 // Return the number of samples written per channel
-                samples_to_write / 2
+                frames
+            }
+        })?;
+
+        stream.set_state_callback({
+            let sinks = self.sinks.clone();
+            let volume = self.volume.clone();
+            let curve = self.options.volume_curve;
+            move |stream, old, new| {
+                info!("Stream state changed: {old:?} -> {new:?}");
+                // Controls only stick once the stream has a node
+                if matches!(old, StreamState::Connecting) {
+                    let _ = set_stream_volume(stream, &volume.get().gains(curve));
+                }
+                match new {
+                    StreamState::Error(e) => {
+                        events::error(&PlayerError::Pipewire(format!(
+                            "Stream error, pausing: {e}"
+                        )));
+                        let _ = stream.set_active(false);
+                    }
+                    StreamState::Paused | StreamState::Streaming => {
+                        sinks.set_node(stream.node_id());
+                    }
+                    _ => {}
+                }
             }
         })?;
 
         stream.connect()?;
 
         let stream = Rc::new(stream);
+        let transport = Rc::new(Transport::new(
+            stream.clone(),
+            decoder,
+            self.mainloop.clone(),
+            self.fade.clone(),
+            self.quitting.clone(),
+        ));
 
-        // Bind the receiver here to avoid weird lifetime stuff. The next song needs a
-        // new receiver and the command thread needs its sender after we are done playing.
-        let _receiver = self.loop_rx.take().unwrap().attach(self.mainloop.loop_(), {
-            let mainloop = self.mainloop.clone();
-            let stream = stream.clone();
-            move |c| match c {
-                Command::Volume(vol) => {
-                    // Cube volume because https://bugzilla.redhat.com/show_bug.cgi?id=502057
-                    let vol = vol * vol * vol;
-                    let _ = stream.set_volume(vol);
+        let fade_timer = self.mainloop.loop_().add_timer({
+            let transport = transport.clone();
+            move |_| transport.poll()
+        });
+        let _ = fade_timer.update_timer(Some(FADE_POLL_INTERVAL), Some(FADE_POLL_INTERVAL));
+
+        let save_position = {
+            let resume = self.resume.clone();
+            let status = self.status.clone();
+            let path = path.to_owned();
+            move || {
+                if let Some(hash) = hash {
+                    resume
+                        .borrow_mut()
+                        .set_position(&path, hash, status.position());
                 }
-                Command::Skip => {
-                    mainloop.quit();
+            }
+        };
+        let resume_timer = self.mainloop.loop_().add_timer({
+            let save_position = save_position.clone();
+            move |_| save_position()
+        });
+        let _ = resume_timer.update_timer(Some(RESUME_SAVE_INTERVAL), Some(RESUME_SAVE_INTERVAL));
+
+        // Tells subscribers when a chapter starts
+        let chapter_timer = self.mainloop.loop_().add_timer({
+            let status = self.status.clone();
+            let last = Cell::new(None);
+            move |_| {
+                let chapter = status.chapter();
+                let index = chapter.as_ref().map(|(index, _)| *index);
+                if index != last.replace(index) {
+                    if let Some((index, chapter)) = chapter {
+                        events::chapter(index + 1, chapter.title.as_deref());
+                    }
                 }
-                Command::Play => {
-                    let _ = stream.set_active(true);
+            }
+        });
+        let _ =
+            chapter_timer.update_timer(Some(CHAPTER_POLL_INTERVAL), Some(CHAPTER_POLL_INTERVAL));
+
+        let session_timer = self.mainloop.loop_().add_timer({
+            let queue = self.next.queue.clone();
+            let status = self.status.clone();
+            move |_| session::save(&queue, &status)
+        });
+        let _ =
+            session_timer.update_timer(Some(SESSION_SAVE_INTERVAL), Some(SESSION_SAVE_INTERVAL));
+
+        self.sinks.set_handler({
+            let transport = transport.clone();
+            let resume = self.options.resume_on_reconnect;
+            let paused_by_monitor = Cell::new(false);
+            move |event| match event {
+                SinkEvent::TargetLost(name) => {
+                    warn!("Sink {name} disappeared, pausing playback");
+                    transport.stop_now();
+                    paused_by_monitor.set(true);
                 }
-                Command::Pause => {
-                    let _ = stream.set_active(false);
+                SinkEvent::TargetReturned(name) if resume && paused_by_monitor.get() => {
+                    info!("Sink {name} is back, resuming playback");
+                    transport.play();
+                    paused_by_monitor.set(false);
                 }
-                Command::Toggle => {
-                    warn!("Toggle not implemented");
+                SinkEvent::TargetReturned(name) => info!("Sink {name} is back"),
+            }
+        });
+
+        self.dispatch.set_handler({
+            let settings = self.settings_handler();
+            let status = self.status.clone();
+            let resume = self.resume.clone();
+            let start_at = self.start_at.clone();
+            let queue = self.next.queue.clone();
+            let path = path.to_owned();
+            move |Request { command, reply }| {
+                let command = match settings.apply(command, Some(&transport)) {
+                    Ok(result) => {
+                        let _ = reply.send(result);
+                        return;
+                    }
+                    Err(command) => command,
+                };
+                let mut result = Ok(());
+                match command {
+                    Command::Seek(_) | Command::Chapter(_) if !seekable => {
+                        result = Err(unseekable())
+                    }
+                    Command::Seek(time) => transport.seek(time),
+                    Command::Chapter(change) => {
+                        match chapter::target(&chapters, status.position(), change) {
+                            Ok(start) => transport.seek(Time::from(start)),
+                            Err(e) => result = Err(e),
+                        }
+                    }
+                    Command::Bookmark(name) => {
+                        let seconds = status.position();
+                        info!("Bookmarked {} at {seconds:.0}s as {name}", path.display());
+                        resume.borrow_mut().set_bookmark(&name, &path, seconds);
+                    }
+                    Command::Goto(name) => match resume.borrow().bookmark(&name).cloned() {
+                        Some(bookmark) if bookmark.path == path && !seekable => {
+                            result = Err(unseekable())
+                        }
+                        Some(bookmark) if bookmark.path == path => {
+                            transport.seek(Time::from(bookmark.seconds))
+                        }
+                        Some(bookmark) => {
+                            queue.lock().unwrap().push_next(bookmark.path.clone());
+                            start_at.set(Some((bookmark.path, bookmark.seconds)));
+                            transport.skip();
+                        }
+                        None => {
+                            result = Err(PlayerError::Queue(format!("No bookmark named {name}")))
+                        }
+                    },
+                    Command::Skip => {
+                        queue.lock().unwrap().skip();
+                        transport.skip();
+                    }
+                    Command::Play => transport.play(),
+                    Command::Pause => transport.pause(),
+                    Command::Toggle => transport.toggle(),
+                    Command::AddResolved(path, songs) => result = enqueue(&queue, &path, songs),
+                    Command::AddFound(query, songs) => {
+                        result = enqueue_found(&queue, &query, songs)
+                    }
+                    Command::Quit => transport.quit(),
+                    _ => {}
                 }
-                _ => {}
+                let _ = reply.send(result);
             }
         });
 
         self.mainloop.run();
+        let _ = stream.disconnect();
+        // Drops the handlers' references to the stream
+        self.dispatch.clear();
+        self.sinks.reset();
 
-        // Update the command thread with the new tx so it can actually send us commands next song
-        let (tx, rx) = channel::channel();
-        self.loop_rx = Some(rx);
-        let _ = task::block_on(self.command_tx.send(Command::UpdatePwSender(tx)));
+        // Songs played to the end start over next time
+        let finished = finished.load(Ordering::Relaxed);
+        match hash {
+            Some(hash) if finished => self.resume.borrow_mut().forget(path, hash),
+            _ => save_position(),
+        }
+        if finished {
+            session::save_next(&self.next.queue);
+        } else {
+            session::save(&self.next.queue, &self.status);
+        }
         Ok(())
     }
+
+    /// Keeps the loop running with nothing playing until songs are added or
+    /// the player is asked to quit
+    pub fn idle(&self) {
+        info!("Queue is empty, waiting for songs");
+        self.status.stop();
+
+        self.dispatch.set_handler({
+            let mainloop = self.mainloop.clone();
+            let settings = self.settings_handler();
+            let resume = self.resume.clone();
+            let start_at = self.start_at.clone();
+            let queue = self.next.queue.clone();
+            let quitting = self.quitting.clone();
+            move |Request { command, reply }| {
+                // Settings still apply to the songs that get added
+                let command = match settings.apply(command, None) {
+                    Ok(result) => {
+                        let _ = reply.send(result);
+                        return;
+                    }
+                    Err(command) => command,
+                };
+                let mut result = Ok(());
+                match command {
+                    Command::AddResolved(path, songs) => {
+                        result = enqueue(&queue, &path, songs);
+                        if result.is_ok() {
+                            mainloop.quit();
+                        }
+                    }
+                    Command::AddFound(query, songs) => {
+                        result = enqueue_found(&queue, &query, songs);
+                        if result.is_ok() {
+                            mainloop.quit();
+                        }
+                    }
+                    // The queue was stopped part way through by a broken song
+                    Command::Play if queue.lock().unwrap().peek().is_some() => mainloop.quit(),
+                    Command::Goto(name) => match resume.borrow().bookmark(&name).cloned() {
+                        Some(bookmark) => {
+                            queue.lock().unwrap().push_next(bookmark.path.clone());
+                            start_at.set(Some((bookmark.path, bookmark.seconds)));
+                            mainloop.quit();
+                        }
+                        None => {
+                            result = Err(PlayerError::Queue(format!("No bookmark named {name}")))
+                        }
+                    },
+                    Command::Quit => {
+                        quitting.set(true);
+                        mainloop.quit();
+                    }
+                    _ => result = Err(PlayerError::Queue("Nothing is playing".to_string())),
+                }
+                let _ = reply.send(result);
+            }
+        });
+
+        self.mainloop.run();
+        self.dispatch.clear();
+    }
+}
+
+type CommandHandler = Box<dyn Fn(Request)>;
+
+/// Routes commands to whatever is running the loop, the current song or the
+/// idle handler
+#[derive(Default)]
+struct Dispatch {
+    handler: RefCell<Option<CommandHandler>>,
+    /// Commands that came in while nothing was there to handle them, waiting
+    /// for the next handler
+    pending: RefCell<VecDeque<Request>>,
+}
+
+impl Dispatch {
+    fn dispatch(&self, request: Request) {
+        match &*self.handler.borrow() {
+            Some(handler) => handler(request),
+            None => self.pending.borrow_mut().push_back(request),
+        }
+    }
+
+    /// Handles commands with `handler` until `clear`, starting with the
+    /// pending ones
+    fn set_handler(&self, handler: impl Fn(Request) + 'static) {
+        *self.handler.borrow_mut() = Some(Box::new(handler));
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        for request in pending {
+            self.dispatch(request);
+        }
+    }
+
+    fn clear(&self) {
+        self.handler.borrow_mut().take();
+    }
+}
+
+/// Handles the commands that change settings which outlast a song, the same
+/// whether a song is playing or not
+struct SettingsHandler {
+    decoder: Rc<RefCell<DecoderSettings>>,
+    fade: Arc<FadeControl>,
+    volume: Rc<Cell<Volume>>,
+    curve: VolumeCurve,
+    status: SharedStatus,
+    queue: SharedQueue,
+}
+
+impl SettingsHandler {
+    /// Applies a settings command to the songs to come and to the one
+    /// `playing`, if any. Any other command is handed back.
+    fn apply(&self, command: Command, playing: Option<&Transport>) -> Result<Reply, Command> {
+        let mut settings = self.decoder.borrow_mut();
+        match command {
+            Command::ReplayGain(mode) => {
+                settings.replay_gain = mode;
+                if let Some(transport) = playing {
+                    transport.decoder().set_replay_gain(mode);
+                }
+            }
+            Command::Crossfade(seconds) => {
+                settings.crossfade = seconds;
+                if let Some(transport) = playing {
+                    transport.decoder().set_crossfade(seconds);
+                }
+            }
+            Command::Eq(change) => {
+                if let Err(e) = settings.eq.apply(change) {
+                    return Ok(Err(eq_error(e)));
+                }
+                if let Some(transport) = playing {
+                    transport.decoder().set_eq(settings.eq.clone());
+                }
+            }
+            Command::Dsp(stages) => {
+                if let Some(transport) = playing {
+                    transport.decoder().set_dsp(stages.clone());
+                }
+                settings.dsp = stages;
+            }
+            Command::Speed(speed) => {
+                settings.speed = speed;
+                if let Some(transport) = playing {
+                    transport.set_speed(speed, settings.speed_mode);
+                }
+            }
+            Command::SpeedMode(mode) => {
+                settings.speed_mode = mode;
+                if let Some(transport) = playing {
+                    transport.set_speed(settings.speed, mode);
+                }
+            }
+            Command::Fade(ms) => self.fade.set_length(ms),
+            Command::Volume(change) => {
+                let volume = change_volume(&self.volume, &self.status, change);
+                if let Some(transport) = playing {
+                    let _ = transport.set_volume(&volume.gains(self.curve));
+                }
+            }
+            Command::Shuffle(shuffle) => self.queue.lock().unwrap().set_shuffle(shuffle),
+            Command::Repeat(repeat) => self.queue.lock().unwrap().set_repeat(repeat),
+            command => return Err(command),
+        }
+        Ok(Ok(()))
+    }
+}
+
+/// Applies a volume change and remembers it for the next stream and the next
+/// run
+fn change_volume(volume: &Cell<Volume>, status: &Status, change: VolumeChange) -> Volume {
+    let mut vol = volume.get();
+    vol.apply(change);
+    volume.set(vol);
+    status.set_volume(vol);
+    vol.save();
+    vol
+}
+
+/// Adds the songs found under `path` to the end of the queue
+fn enqueue(queue: &SharedQueue, path: &Path, songs: Vec<PathBuf>) -> Reply {
+    if songs.is_empty() {
+        return Err(PlayerError::Queue(format!(
+            "No songs under {}",
+            path.display()
+        )));
+    }
+    info!("Added {} songs from {}", songs.len(), path.display());
+    queue.lock().unwrap().append(songs);
+    Ok(())
+}
+
+/// Adds the songs in the library that matched a query
+fn enqueue_found(queue: &SharedQueue, query: &Query, entries: Vec<PathBuf>) -> Reply {
+    if entries.is_empty() {
+        return Err(PlayerError::Queue(format!("No songs match {query}")));
+    }
+    info!("Added {} songs matching {query}", entries.len());
+    queue.lock().unwrap().append(entries);
+    Ok(())
+}
+
+/// Equalizer changes fail because of what the command asked for, e.g. a
+/// preset that doesn't exist, or because a profile can't be read
+fn eq_error(e: Box<dyn Error>) -> PlayerError {
+    let message = format!("Failed to change the equalizer: {e}");
+    match e.downcast::<io::Error>() {
+        Ok(e) => PlayerError::Io(io::Error::new(e.kind(), message)),
+        Err(_) => PlayerError::Protocol(message),
+    }
 }
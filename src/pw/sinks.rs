@@ -0,0 +1,186 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use log::debug;
+use pipewire::{
+    core::Core,
+    keys,
+    registry::{GlobalObject, Listener, Registry},
+    spa::utils::dict::DictRef,
+    types::ObjectType,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkEvent {
+    /// The sink our stream was linked to was removed from the graph
+    TargetLost(String),
+    /// A sink with the same name as the one we lost was added back
+    TargetReturned(String),
+}
+
+type SinkHandler = Rc<dyn Fn(SinkEvent)>;
+
+#[derive(Default)]
+struct SinkState {
+    /// Sink node id -> node name
+    sinks: HashMap<u32, String>,
+    /// Link id -> (output node, input node)
+    links: HashMap<u32, (u32, u32)>,
+    /// Node id of our own stream, once it is known
+    node: Option<u32>,
+    /// Node id of the sink our stream is linked to
+    target: Option<u32>,
+    /// Name of the sink we were linked to before it disappeared
+    lost: Option<String>,
+    handler: Option<SinkHandler>,
+    /// Events waiting for the state to be released, since the handler may
+    /// call back into the monitor
+    pending: Vec<SinkEvent>,
+}
+
+impl SinkState {
+    fn emit(&mut self, event: SinkEvent) {
+        debug!("Sink event: {event:?}");
+        self.pending.push(event);
+    }
+
+    fn update_target(&mut self) {
+        let Some(node) = self.node else {
+            return;
+        };
+
+        let target = self
+            .links
+            .values()
+            .find(|(output, input)| *output == node && self.sinks.contains_key(input))
+            .map(|(_, input)| *input);
+
+        if target.is_some() && target != self.target {
+            debug!(
+                "Stream is linked to sink {:?}",
+                target.map(|t| &self.sinks[&t])
+            );
+            self.target = target;
+        }
+    }
+
+    fn global_added(&mut self, global: &GlobalObject<&DictRef>) {
+        let Some(props) = global.props else {
+            return;
+        };
+
+        match global.type_ {
+            ObjectType::Node if props.get(*keys::MEDIA_CLASS) == Some("Audio/Sink") => {
+                let name = props.get(*keys::NODE_NAME).unwrap_or_default().to_owned();
+                debug!("Sink added: {name} ({})", global.id);
+
+                if self.lost.as_ref() == Some(&name) {
+                    self.lost = None;
+                    self.emit(SinkEvent::TargetReturned(name.clone()));
+                }
+                self.sinks.insert(global.id, name);
+                self.update_target();
+            }
+            ObjectType::Link => {
+                let parse = |key| props.get(key).and_then(|v: &str| v.parse().ok());
+                if let (Some(output), Some(input)) = (
+                    parse(*keys::LINK_OUTPUT_NODE),
+                    parse(*keys::LINK_INPUT_NODE),
+                ) {
+                    self.links.insert(global.id, (output, input));
+                    self.update_target();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn global_removed(&mut self, id: u32) {
+        self.links.remove(&id);
+
+        if let Some(name) = self.sinks.remove(&id) {
+            debug!("Sink removed: {name} ({id})");
+            if self.target == Some(id) {
+                self.target = None;
+                self.lost = Some(name.clone());
+                self.emit(SinkEvent::TargetLost(name));
+            }
+        }
+    }
+}
+
+/// Runs the handler for the pending events after the state is released
+fn dispatch(state: &RefCell<SinkState>) {
+    let (events, handler) = {
+        let mut state = state.borrow_mut();
+        (std::mem::take(&mut state.pending), state.handler.clone())
+    };
+    if let Some(handler) = handler {
+        for event in events {
+            handler(event);
+        }
+    }
+}
+
+/// Watches the registry for sinks coming and going and tracks which sink our
+/// stream is currently linked to.
+pub struct SinkMonitor {
+    _registry: Registry,
+    _listener: Listener,
+    state: Rc<RefCell<SinkState>>,
+}
+
+impl SinkMonitor {
+    pub fn new(core: &Core) -> Result<Self, pipewire::Error> {
+        let registry = core.get_registry()?;
+        let state = Rc::new(RefCell::new(SinkState::default()));
+
+        let listener = registry
+            .add_listener_local()
+            .global({
+                let state = state.clone();
+                move |global| {
+                    state.borrow_mut().global_added(global);
+                    dispatch(&state);
+                }
+            })
+            .global_remove({
+                let state = state.clone();
+                move |id| {
+                    state.borrow_mut().global_removed(id);
+                    dispatch(&state);
+                }
+            })
+            .register();
+
+        Ok(Self {
+            _registry: registry,
+            _listener: listener,
+            state,
+        })
+    }
+
+    /// Sets the node id of the stream whose target should be tracked.
+    pub fn set_node(&self, node: u32) {
+        let mut state = self.state.borrow_mut();
+        if state.node != Some(node) {
+            state.node = Some(node);
+            state.target = None;
+            state.update_target();
+        }
+    }
+
+    pub fn set_handler<F>(&self, handler: F)
+    where
+        F: Fn(SinkEvent) + 'static,
+    {
+        self.state.borrow_mut().handler = Some(Rc::new(handler));
+    }
+
+    /// Forgets the current stream and handler. Called once a song is done.
+    pub fn reset(&self) {
+        let mut state = self.state.borrow_mut();
+        state.node = None;
+        state.target = None;
+        state.handler = None;
+    }
+}
@@ -9,7 +9,7 @@ use pipewire::{
         pod::{serialize::GenError, Pod},
         utils::{result::SpaResult, Direction},
     },
-    stream::{Stream as PwStream, StreamFlags, StreamListener, StreamRef},
+    stream::{Stream as PwStream, StreamFlags, StreamListener, StreamRef, StreamState},
 };
 
 use super::audio_info::AudioInfo;
@@ -22,6 +22,7 @@ pub struct StreamMetadata {
 
 pub struct Stream {
     _listener: StreamListener<()>,
+    _state_listener: Option<StreamListener<()>>,
     metadata: StreamMetadata,
     stream: PwStream,
 }
@@ -44,6 +45,7 @@ impl Stream {
 
         Ok(Self {
             _listener: listener,
+            _state_listener: None,
             stream,
             metadata,
         })
@@ -66,6 +68,23 @@ impl Stream {
         Ok(())
     }
 
+    pub fn set_state_callback<F>(&mut self, mut callback: F) -> Result<(), pipewire::Error>
+    where
+        F: FnMut(&StreamRef, StreamState, StreamState) + 'static,
+    {
+        let listener = self
+            .stream
+            .add_local_listener()
+            .state_changed(move |stream, _, old, new| callback(stream, old, new))
+            .register()
+            .map_err(|e| {
+                warn!("Failed to set state callback on stream: {e:?}");
+                e
+            })?;
+        self._state_listener = Some(listener);
+        Ok(())
+    }
+
     pub fn set_active(&self, active: bool) -> Result<(), pipewire::Error> {
         self.stream.set_active(active).map_err(|e| {
             warn!("Error setting stream active state: {e:?}");
@@ -73,13 +92,9 @@ impl Stream {
         })
     }
 
-    pub fn set_volume(&self, volume: f32) -> Result<(), pipewire::Error> {
-        self.stream
-            .set_control(spa::sys::SPA_PROP_channelVolumes, &[volume, volume])
-            .map_err(|e| {
-                warn!("Error setting stream volume: {e:?}");
-                e
-            })
+    /// Sets the gain of each channel
+    pub fn set_volume(&self, volumes: &[f32]) -> Result<(), pipewire::Error> {
+        set_stream_volume(&self.stream, volumes)
     }
 
     pub fn set_name<T: AsRef<str>>(&self, name: T) -> Result<(), pipewire::Error> {
@@ -114,6 +129,13 @@ impl Stream {
         )
     }
 
+    pub fn disconnect(&self) -> Result<(), pipewire::Error> {
+        self.stream.disconnect().map_err(|e| {
+            warn!("Error disconnecting stream: {e:?}");
+            e
+        })
+    }
+
     pub fn params(&self) -> Result<Vec<u8>, GenError> {
         AudioInfo::new(
             self.metadata.rate,
@@ -124,6 +146,16 @@ impl Stream {
     }
 }
 
+/// Sets the volume from stream callbacks, which only get a `StreamRef`
+pub fn set_stream_volume(stream: &StreamRef, volumes: &[f32]) -> Result<(), pipewire::Error> {
+    stream
+        .set_control(spa::sys::SPA_PROP_channelVolumes, volumes)
+        .map_err(|e| {
+            warn!("Error setting stream volume: {e:?}");
+            e
+        })
+}
+
 fn stream_process_callback<F>(stream: &StreamRef, metadata: StreamMetadata, user_callback: &mut F)
 where
     F: FnMut(&mut [f32]) -> usize + 'static,
@@ -137,12 +169,18 @@ where
     };
 
     let stride = std::mem::size_of::<f32>() * metadata.channels as usize;
+    // Frames wanted this cycle, which is 0 when the server doesn't say
+    let requested = buffer.requested() as usize;
     let datas = buffer.datas_mut();
     let data = &mut datas[0];
 
     let mut samples_written = 0;
     if let Some(slice) = data.data() {
-        let slice = unsafe { &mut *(slice as *mut _ as *mut [f32]) };
+        let mut len = slice.len() / std::mem::size_of::<f32>();
+        if requested > 0 {
+            len = len.min(requested * metadata.channels as usize);
+        }
+        let slice = unsafe { std::slice::from_raw_parts_mut(slice.as_mut_ptr() as *mut f32, len) };
         samples_written = (user_callback)(slice);
     }
 
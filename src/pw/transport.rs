@@ -0,0 +1,190 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    mem::discriminant,
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::debug;
+use pipewire::main_loop::MainLoop;
+use symphonia::core::units::Time;
+
+use super::stream::Stream;
+use crate::{decoder::DecoderWorker, dsp::speed::SpeedMode, fade::FadeControl};
+
+/// How often to check whether a fade-out has drained
+pub const FADE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Things that wait for the fade-out to drain before they happen
+#[derive(Debug, Clone, Copy)]
+enum FadeAction {
+    Pause,
+    Skip,
+    Seek(Time),
+    Speed(f32, SpeedMode),
+    Quit,
+}
+
+impl FadeAction {
+    /// Whether this stops the song, after which nothing else matters
+    fn ends_song(self) -> bool {
+        matches!(self, Self::Skip | Self::Quit)
+    }
+}
+
+/// Play/pause/seek/skip handling for the current song. Lives on the pipewire
+/// loop thread.
+pub struct Transport {
+    stream: Rc<Stream>,
+    decoder: DecoderWorker,
+    mainloop: MainLoop,
+    fade: Arc<FadeControl>,
+    /// Run in order once the fade-out has drained
+    pending: RefCell<VecDeque<FadeAction>>,
+    paused: Cell<bool>,
+    /// Set by `quit`, so no more songs get played
+    quitting: Rc<Cell<bool>>,
+}
+
+impl Transport {
+    pub fn new(
+        stream: Rc<Stream>,
+        decoder: DecoderWorker,
+        mainloop: MainLoop,
+        fade: Arc<FadeControl>,
+        quitting: Rc<Cell<bool>>,
+    ) -> Self {
+        Self {
+            stream,
+            decoder,
+            mainloop,
+            fade,
+            pending: RefCell::default(),
+            paused: Cell::new(false),
+            quitting,
+        }
+    }
+
+    pub fn decoder(&self) -> &DecoderWorker {
+        &self.decoder
+    }
+
+    /// Sets the gain of each channel of the stream
+    pub fn set_volume(&self, gains: &[f32]) -> Result<(), pipewire::Error> {
+        self.stream.set_volume(gains)
+    }
+
+    pub fn play(&self) {
+        if self.paused.get() && self.stream.set_active(true).is_ok() {
+            self.paused.set(false);
+        }
+        let mut pending = self.pending.borrow_mut();
+        pending.retain(|action| !matches!(action, FadeAction::Pause));
+        // Whatever else is waiting fades back in or stops the song itself
+        if pending.is_empty() {
+            self.fade.fade_in();
+        }
+    }
+
+    pub fn pause(&self) {
+        self.fade_out_then(FadeAction::Pause);
+    }
+
+    pub fn toggle(&self) {
+        let pausing = self
+            .pending
+            .borrow()
+            .iter()
+            .any(|action| matches!(action, FadeAction::Pause));
+        if self.paused.get() || pausing {
+            self.play();
+        } else {
+            self.pause();
+        }
+    }
+
+    pub fn skip(&self) {
+        self.fade_out_then(FadeAction::Skip);
+    }
+
+    pub fn seek(&self, time: Time) {
+        self.fade_out_then(FadeAction::Seek(time));
+    }
+
+    pub fn set_speed(&self, speed: f32, mode: SpeedMode) {
+        self.fade_out_then(FadeAction::Speed(speed, mode));
+    }
+
+    pub fn quit(&self) {
+        // Set right away so the song ending first still counts as quitting
+        self.quitting.set(true);
+        self.fade_out_then(FadeAction::Quit);
+    }
+
+    /// Deactivates the stream right away, without fading. A stopped stream
+    /// never drains, so whatever waited for that runs now.
+    pub fn stop_now(&self) {
+        if self.stream.set_active(false).is_ok() {
+            self.paused.set(true);
+        }
+        self.run_pending();
+    }
+
+    /// Runs the pending actions once the fade-out has drained. Called
+    /// periodically from a timer on the loop.
+    pub fn poll(&self) {
+        if !self.pending.borrow().is_empty() && self.fade.is_silent() {
+            self.run_pending();
+        }
+    }
+
+    fn fade_out_then(&self, action: FadeAction) {
+        // Nothing is playing, so there is nothing to fade
+        if self.paused.get() {
+            self.run(action);
+            return;
+        }
+
+        let mut pending = self.pending.borrow_mut();
+        if pending.iter().any(|pending| pending.ends_song()) {
+            return;
+        }
+        if action.ends_song() {
+            pending.clear();
+        }
+        // A later seek or speed replaces an earlier one
+        pending.retain(|pending| discriminant(pending) != discriminant(&action));
+        pending.push_back(action);
+        drop(pending);
+        self.fade.fade_out();
+    }
+
+    fn run_pending(&self) {
+        let pending = self.pending.take();
+        for action in pending {
+            self.run(action);
+        }
+    }
+
+    fn run(&self, action: FadeAction) {
+        debug!("Fade drained, running {action:?}");
+        match action {
+            FadeAction::Pause => {
+                if self.stream.set_active(false).is_ok() {
+                    self.paused.set(true);
+                }
+            }
+            FadeAction::Seek(time) => {
+                self.decoder.seek(time);
+                self.fade.fade_in();
+            }
+            FadeAction::Speed(speed, mode) => {
+                self.decoder.set_speed(speed, mode);
+                self.fade.fade_in();
+            }
+            FadeAction::Skip | FadeAction::Quit => self.mainloop.quit(),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use rand::seq::SliceRandom;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Repeat {
+    #[default]
+    Off,
+    /// Start over from the first song after the last one
+    All,
+    /// Play the current song again and again
+    One,
+}
+
+impl FromStr for Repeat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "all" => Ok(Self::All),
+            "one" => Ok(Self::One),
+            _ => Err(format!("Unknown repeat mode: {s}")),
+        }
+    }
+}
+
+impl Display for Repeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Off => "off",
+            Self::All => "all",
+            Self::One => "one",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The songs to play, in order
+#[derive(Debug, Default)]
+pub struct Queue {
+    entries: Vec<PathBuf>,
+    /// Index of the song that will be popped next
+    next: usize,
+    /// Index of the song that was popped last
+    current: Option<usize>,
+    shuffle: bool,
+    repeat: Repeat,
+}
+
+pub type SharedQueue = Arc<Mutex<Queue>>;
+
+impl Queue {
+    pub fn new(entries: Vec<PathBuf>) -> Self {
+        Self {
+            entries,
+            ..Self::default()
+        }
+    }
+
+    /// A queue saved part way through, `next` being the song to start with
+    pub fn restore(entries: Vec<PathBuf>, next: usize, shuffle: bool, repeat: Repeat) -> Self {
+        Self {
+            entries,
+            next,
+            current: None,
+            shuffle,
+            repeat,
+        }
+    }
+
+    fn next_index(&self) -> Option<usize> {
+        match self.repeat {
+            Repeat::One if self.current.is_some() => self.current,
+            Repeat::All if self.next >= self.entries.len() && !self.entries.is_empty() => Some(0),
+            _ => (self.next < self.entries.len()).then_some(self.next),
+        }
+    }
+
+    /// Takes the next song off the queue
+    pub fn pop(&mut self) -> Option<PathBuf> {
+        let index = self.next_index()?;
+        self.current = Some(index);
+        self.next = index + 1;
+        Some(self.entries[index].clone())
+    }
+
+    /// Moves on to the next song even when repeating the current one
+    pub fn skip(&mut self) {
+        self.current = None;
+    }
+
+    /// Puts a song in front of the rest of the queue
+    pub fn push_next(&mut self, entry: PathBuf) {
+        self.entries.insert(self.next, entry);
+        // Otherwise repeating the current song would never get to it
+        self.current = None;
+    }
+
+    /// Adds songs to the end of the queue, shuffled among themselves when
+    /// shuffling
+    pub fn append(&mut self, mut entries: Vec<PathBuf>) {
+        if self.shuffle {
+            entries.shuffle(&mut rand::thread_rng());
+        }
+        self.entries.append(&mut entries);
+    }
+
+    /// The song that will be popped next
+    pub fn peek(&self) -> Option<&PathBuf> {
+        self.next_index().map(|i| &self.entries[i])
+    }
+
+    pub fn entries(&self) -> &[PathBuf] {
+        &self.entries
+    }
+
+    /// Index of the song that is playing, or of the one that plays first
+    /// when nothing has been popped yet
+    pub fn current(&self) -> usize {
+        self.current.unwrap_or(self.next)
+    }
+
+    /// Index of the song that will be popped next, past the end when there
+    /// is none
+    pub fn upcoming(&self) -> usize {
+        self.next_index().unwrap_or(self.entries.len())
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    /// Shuffles the songs that haven't been played yet, or puts them back
+    /// in path order
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        let next = self.next.min(self.entries.len());
+        let upcoming = &mut self.entries[next..];
+        if shuffle {
+            upcoming.shuffle(&mut rand::thread_rng());
+        } else {
+            upcoming.sort();
+        }
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_leaves_repeated_song() {
+        let mut queue = Queue::new(vec!["a".into(), "b".into()]);
+        queue.set_repeat(Repeat::One);
+        assert_eq!(queue.pop(), Some("a".into()));
+        assert_eq!(queue.pop(), Some("a".into()));
+        queue.skip();
+        assert_eq!(queue.pop(), Some("b".into()));
+        assert_eq!(queue.pop(), Some("b".into()));
+    }
+
+    #[test]
+    fn upcoming_after_the_last_song() {
+        let mut queue = Queue::new(vec!["a".into()]);
+        queue.pop();
+        assert_eq!(queue.upcoming(), 1);
+        queue.set_repeat(Repeat::All);
+        assert_eq!(queue.upcoming(), 0);
+    }
+}
@@ -0,0 +1,347 @@
+//! Runs songs through the decoder into a WAV or FLAC file instead of PipeWire,
+//! e.g. to make a mix or to check the output of the pipeline without an audio
+//! server.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    cli::Options,
+    decoder::{DecoderSettings, DecoderWorker, Handoff, NextSong, OnDecodeError, Preloaded},
+    dsp::speed::{Speed, SpeedMode},
+    error::PlayerError,
+    events,
+    flac::FlacWriter,
+    queue::Queue,
+    ring,
+    song::SongReader,
+    status::Status,
+};
+
+/// Seconds of audio the decoder may get ahead of the writer
+const BUFFER_SECONDS: usize = 2;
+/// How long to wait when the decoder hasn't caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// WAVE_FORMAT_IEEE_FLOAT
+const FORMAT_FLOAT: u16 = 3;
+const CHANNELS: u16 = 2;
+
+/// Writes interleaved f32 samples as they are, so the file holds exactly what
+/// would have been sent to PipeWire
+struct WavWriter {
+    file: BufWriter<File>,
+    frames: u64,
+}
+
+impl WavWriter {
+    // Offsets of the sizes that are only known once everything is written
+    const RIFF_SIZE: u64 = 4;
+    const FACT_FRAMES: u64 = 46;
+    const DATA_SIZE: u64 = 54;
+    const HEADER_SIZE: u64 = 58;
+    const BLOCK_ALIGN: u64 = CHANNELS as u64 * 4;
+
+    fn create(path: &Path, rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = CHANNELS * 4;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&18u32.to_le_bytes())?;
+        file.write_all(&FORMAT_FLOAT.to_le_bytes())?;
+        file.write_all(&CHANNELS.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?;
+        // No extension
+        file.write_all(&0u16.to_le_bytes())?;
+
+        // Required for anything that isn't PCM
+        file.write_all(b"fact")?;
+        file.write_all(&4u32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file, frames: 0 })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let frames = self.frames + (samples.len() / CHANNELS as usize) as u64;
+        // The RIFF size is the largest of the sizes in the header
+        if Self::HEADER_SIZE - 8 + frames * Self::BLOCK_ALIGN > u32::MAX as u64 {
+            let e = "The render is too long for a WAV file, which can't go past 4 GiB";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.frames = frames;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        // `write` made sure these fit
+        let data_size = (self.frames * Self::BLOCK_ALIGN) as u32;
+        for (offset, value) in [
+            (Self::RIFF_SIZE, Self::HEADER_SIZE as u32 - 8 + data_size),
+            (Self::FACT_FRAMES, self.frames as u32),
+            (Self::DATA_SIZE, data_size),
+        ] {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.file.flush()
+    }
+}
+
+/// The file formats that can be rendered to, picked by the output's extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Wav,
+    Flac,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("wav") => Ok(Self::Wav),
+            Some("flac") => Ok(Self::Flac),
+            Some(other) => Err(format!(
+                "Can't render to .{other} files, only .wav and .flac"
+            )),
+            None => Err("The output file needs a .wav or .flac extension".to_string()),
+        }
+    }
+}
+
+enum Writer {
+    Wav(WavWriter),
+    Flac(FlacWriter),
+}
+
+impl Writer {
+    fn create(format: Format, path: &Path, rate: u32) -> io::Result<Self> {
+        Ok(match format {
+            Format::Wav => Self::Wav(WavWriter::create(path, rate)?),
+            Format::Flac => Self::Flac(FlacWriter::create(path, rate)?),
+        })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        match self {
+            Self::Wav(writer) => writer.write(samples),
+            Self::Flac(writer) => writer.write(samples),
+        }
+    }
+
+    fn frames(&self) -> u64 {
+        match self {
+            Self::Wav(writer) => writer.frames,
+            Self::Flac(writer) => writer.frames(),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Wav(writer) => writer.finish(),
+            Self::Flac(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Renders every song under `inputs`, in order, into a WAV or FLAC file at
+/// `output`. The file has the sample rate of the first song and later songs
+/// are resampled to it.
+pub fn render(inputs: &[String], output: &Path, options: &Options) -> Result<(), Box<dyn Error>> {
+    let format = Format::from_path(output)?;
+
+    let mut files = vec![];
+    for input in inputs {
+        files.extend(crate::handle_input_path(input)?);
+    }
+
+    let status = Arc::new(Status::default());
+    let settings = DecoderSettings::new(options);
+    let next = NextSong {
+        queue: Arc::new(Mutex::new(Queue::new(files))),
+        handoff: Handoff::default(),
+    };
+
+    let mut writer: Option<(Writer, u32)> = None;
+    let mut songs = 0;
+    loop {
+        let Some(file) = next.queue.lock().unwrap().pop() else {
+            break;
+        };
+
+        let preloaded = next.handoff.lock().unwrap().take();
+        let song = match preloaded.filter(|p| p.path == file) {
+            Some(Preloaded { song, .. }) => Ok(song),
+            None => SongReader::open(&file),
+        };
+        let song = song.and_then(|song| {
+            if song.channels == CHANNELS as u32 {
+                Ok(song)
+            } else {
+                let what = "Only 2 channel tracks are supported";
+                Err(PlayerError::UnsupportedFormat(what.into()))
+            }
+        });
+        let song = match song {
+            Ok(song) => song,
+            Err(e) => {
+                events::song_error(&file, &e);
+                if settings.on_decode_error == OnDecodeError::Stop {
+                    warn!("Stopped after a song that couldn't be opened");
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let (writer, rate) = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert((Writer::create(format, output, song.rate)?, song.rate)),
+        };
+        info!("Rendering {}", file.display());
+        render_song(file, song, *rate, writer, &status, &settings, &next)?;
+        songs += 1;
+
+        if status.take_queue_stopped() {
+            warn!("Stopped after a song that couldn't be decoded");
+            break;
+        }
+    }
+
+    let Some((writer, rate)) = writer else {
+        return Err("Nothing to render".into());
+    };
+    let seconds = writer.frames() as f64 / rate as f64;
+    writer.finish()?;
+    println!(
+        "Rendered {songs} songs, {seconds:.1} seconds, to {}",
+        output.display()
+    );
+    Ok(())
+}
+
+fn render_song(
+    path: PathBuf,
+    song: SongReader,
+    rate: u32,
+    writer: &mut Writer,
+    status: &Arc<Status>,
+    settings: &DecoderSettings,
+    next: &NextSong,
+) -> io::Result<()> {
+    // Same as PipeWire would, but with the linear resampler the speed stage uses
+    let mut resampler = (song.rate != rate).then(|| {
+        let ratio = song.rate as f32 / rate as f32;
+        Speed::new(ratio, SpeedMode::Pitch, rate, CHANNELS as usize)
+    });
+
+    status.start_song(
+        song.name.clone(),
+        song.rate,
+        song.position(),
+        settings.speed,
+    );
+    let capacity = song.rate as usize * CHANNELS as usize * BUFFER_SECONDS;
+    let (producer, mut consumer) = ring::ring(capacity);
+    let _decoder = DecoderWorker::spawn(
+        path,
+        song,
+        producer,
+        status.clone(),
+        settings.clone(),
+        next.clone(),
+    );
+
+    let mut buffer = vec![0.0; capacity];
+    let mut resampled = vec![];
+    loop {
+        let read = consumer.pop(&mut buffer);
+        if read == 0 {
+            if consumer.is_finished() {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        match resampler.as_mut() {
+            Some(resampler) => {
+                resampled.clear();
+                resampler.process(&buffer[..read], &mut resampled);
+                writer.write(&resampled)?;
+            }
+            None => writer.write(&buffer[..read])?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::test_song;
+
+    fn samples(path: &Path) -> Vec<f32> {
+        let mut song = SongReader::open(path).unwrap();
+        let mut samples = vec![];
+        while let Ok(chunk) = song.next_chunk() {
+            samples.extend_from_slice(chunk);
+        }
+        samples
+    }
+
+    #[test]
+    fn renders_songs_in_order_as_they_are() {
+        // Created out of order, so only sorting puts them back in it
+        let b = test_song("render/songs/b.wav", 44100, 30000);
+        let a = test_song("render/songs/a.wav", 44100, 20000);
+        let songs = a.parent().unwrap();
+        let dir = songs.parent().unwrap();
+        // Leaves out any equalizer saved in the config directory
+        let flat = dir.join("flat.txt");
+        std::fs::write(&flat, "").unwrap();
+        let options = Options {
+            eq: Some(flat.to_string_lossy().into_owned()),
+            ..Options::default()
+        };
+
+        // 16-bit samples fit both formats exactly
+        let expected: Vec<f32> = samples(&a).into_iter().chain(samples(&b)).collect();
+        assert_eq!(expected.len(), 50000 * 2);
+        for output in ["out.wav", "out.flac"] {
+            let output = dir.join(output);
+            let inputs = [songs.to_string_lossy().into_owned()];
+            render(&inputs, &output, &options).unwrap();
+            assert_eq!(samples(&output), expected, "{}", output.display());
+        }
+    }
+
+    #[test]
+    fn picks_the_format_by_extension() {
+        assert_eq!(Format::from_path(Path::new("a.wav")), Ok(Format::Wav));
+        assert_eq!(Format::from_path(Path::new("a.flac")), Ok(Format::Flac));
+        assert!(Format::from_path(Path::new("a.mp3")).is_err());
+        assert!(Format::from_path(Path::new("a")).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::{fmt::Display, fmt::Write, path::Path, str::FromStr};
+
+use symphonia::core::meta::StandardTagKey;
+
+/// Which ReplayGain values to apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Album gain when the song has it, track gain otherwise
+    Auto,
+}
+
+impl FromStr for ReplayGainMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "track" => Ok(Self::Track),
+            "album" => Ok(Self::Album),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("Unknown replay-gain mode: {s}")),
+        }
+    }
+}
+
+impl Display for ReplayGainMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Off => "off",
+            Self::Track => "track",
+            Self::Album => "album",
+            Self::Auto => "auto",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// ReplayGain 2.0 values read from a song's tags. Gains are in dB and peaks
+/// are linear sample values.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Picks up the value of a tag if it is a ReplayGain tag
+    pub fn read_tag(&mut self, key: StandardTagKey, value: &str) {
+        let slot = match key {
+            StandardTagKey::ReplayGainTrackGain => &mut self.track_gain,
+            StandardTagKey::ReplayGainTrackPeak => &mut self.track_peak,
+            StandardTagKey::ReplayGainAlbumGain => &mut self.album_gain,
+            StandardTagKey::ReplayGainAlbumPeak => &mut self.album_peak,
+            _ => return,
+        };
+        *slot = parse_value(value);
+    }
+
+    /// Linear factor to scale samples by for the given mode. The gain is
+    /// lowered if it would push the song's peak above full scale.
+    pub fn factor(&self, mode: ReplayGainMode) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (self.track_gain, self.track_peak),
+            ReplayGainMode::Album => (self.album_gain, self.album_peak),
+            ReplayGainMode::Auto if self.album_gain.is_some() => (self.album_gain, self.album_peak),
+            ReplayGainMode::Auto => (self.track_gain, self.track_peak),
+        };
+
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+
+        let factor = 10f32.powf(gain / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// Parses values like "-6.54 dB" or "0.988547"
+fn parse_value(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok()
+}
+
+/// Scales samples by `factor`, clamping anything that would still clip.
+pub fn apply(samples: &mut [f32], factor: f32) {
+    if factor == 1.0 {
+        return;
+    }
+
+    for sample in samples {
+        *sample = (*sample * factor).clamp(-1.0, 1.0);
+    }
+}
+
+/// Loudness that ReplayGain 2.0 normalizes to, in LUFS
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+/// Name of the per-directory cache written by `pwplayer scan`
+pub const SIDECAR_NAME: &str = ".replaygain";
+
+/// Looks up the gain of a song in the sidecar cache next to it
+pub fn read_sidecar(path: &Path) -> Option<ReplayGain> {
+    let name = path.file_name()?.to_str()?;
+    let sidecar = std::fs::read_to_string(path.with_file_name(SIDECAR_NAME)).ok()?;
+
+    sidecar.lines().find_map(|line| {
+        let mut fields = line.splitn(5, '\t');
+        let mut value = || fields.next().and_then(|v| v.parse().ok());
+        let gain = ReplayGain {
+            track_gain: value(),
+            track_peak: value(),
+            album_gain: value(),
+            album_peak: value(),
+        };
+        (fields.next() == Some(name)).then_some(gain)
+    })
+}
+
+/// Writes the sidecar cache for the songs in `dir`, one tab separated line
+/// per song: track gain, track peak, album gain, album peak and file name.
+pub fn write_sidecar(dir: &Path, songs: &[(String, ReplayGain)]) -> std::io::Result<()> {
+    let field = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+
+    let mut sidecar = String::new();
+    for (name, gain) in songs {
+        let _ = writeln!(
+            sidecar,
+            "{}\t{}\t{}\t{}\t{name}",
+            field(gain.track_gain),
+            field(gain.track_peak),
+            field(gain.album_gain),
+            field(gain.album_peak),
+        );
+    }
+
+    std::fs::write(dir.join(SIDECAR_NAME), sidecar)
+}
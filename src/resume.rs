@@ -0,0 +1,227 @@
+//! Remembers where long files were left off, and named bookmarks.
+//!
+//! Positions are keyed by path and by a hash of the file's contents, so a
+//! file keeps its position when it is moved or renamed.
+
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::{cue, state};
+
+const POSITIONS_FILE: &str = "positions";
+const BOOKMARKS_FILE: &str = "bookmarks";
+/// How much of each end of a file goes into its hash
+const HASH_BLOCK: u64 = 64 * 1024;
+
+/// Hashes the size and both ends of a file. Reading the whole thing would
+/// take too long for the files this is meant for. Tracks of a CUE sheet hash
+/// the sheet along with the track number.
+pub fn content_hash(path: &Path) -> std::io::Result<u64> {
+    // FNV-1a, which unlike std's hasher is stable across releases
+    fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+        bytes.iter().fold(hash, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    let (path, track) = match cue::split(path) {
+        Some((sheet, number)) => (sheet, Some(number)),
+        None => (path.to_owned(), None),
+    };
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hash = fnv(0xcbf29ce484222325, &size.to_le_bytes());
+
+    let mut block = vec![];
+    file.by_ref().take(HASH_BLOCK).read_to_end(&mut block)?;
+    hash = fnv(hash, &block);
+
+    if size > HASH_BLOCK {
+        block.clear();
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(HASH_BLOCK).max(HASH_BLOCK),
+        ))?;
+        file.read_to_end(&mut block)?;
+        hash = fnv(hash, &block);
+    }
+
+    if let Some(track) = track {
+        hash = fnv(hash, &track.to_le_bytes());
+    }
+    Ok(hash)
+}
+
+#[derive(Debug, Clone)]
+struct Position {
+    path: PathBuf,
+    hash: u64,
+    seconds: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Bookmark {
+    pub name: String,
+    pub path: PathBuf,
+    pub seconds: f64,
+}
+
+/// Both kinds of saved positions. Each is a state file with one tab
+/// separated line per entry, ending in the path.
+#[derive(Debug, Default)]
+pub struct ResumeStore {
+    positions: Vec<Position>,
+    bookmarks: Vec<Bookmark>,
+}
+
+impl ResumeStore {
+    pub fn load() -> Self {
+        let lines = |name| -> Vec<Vec<String>> {
+            state::read(name)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.splitn(3, '\t').map(str::to_string).collect())
+                .collect()
+        };
+
+        let positions = lines(POSITIONS_FILE)
+            .into_iter()
+            .filter_map(|fields| match &fields[..] {
+                [hash, seconds, path] => Some(Position {
+                    path: path.into(),
+                    hash: u64::from_str_radix(hash, 16).ok()?,
+                    seconds: seconds.parse().ok()?,
+                }),
+                _ => None,
+            })
+            .collect();
+        let bookmarks = lines(BOOKMARKS_FILE)
+            .into_iter()
+            .filter_map(|fields| match &fields[..] {
+                [name, seconds, path] => Some(Bookmark {
+                    name: name.clone(),
+                    path: path.into(),
+                    seconds: seconds.parse().ok()?,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            positions,
+            bookmarks,
+        }
+    }
+
+    fn save_positions(&self) {
+        let mut saved = String::new();
+        for p in &self.positions {
+            let _ = writeln!(saved, "{:x}\t{}\t{}", p.hash, p.seconds, p.path.display());
+        }
+        if let Err(e) = state::write(POSITIONS_FILE, &saved) {
+            warn!("Failed to save resume positions: {e:?}");
+        }
+    }
+
+    fn save_bookmarks(&self) {
+        let mut saved = String::new();
+        for b in &self.bookmarks {
+            let _ = writeln!(saved, "{}\t{}\t{}", b.name, b.seconds, b.path.display());
+        }
+        if let Err(e) = state::write(BOOKMARKS_FILE, &saved) {
+            warn!("Failed to save bookmarks: {e:?}");
+        }
+    }
+
+    /// The contents have to match, so a file replaced at the same path starts
+    /// over. The path only picks between copies, or finds a moved file.
+    fn find(&self, path: &Path, hash: u64) -> Option<usize> {
+        let same = |p: &Position| p.hash == hash;
+        let by_path = self
+            .positions
+            .iter()
+            .position(|p| same(p) && p.path == path);
+        by_path.or_else(|| self.positions.iter().position(same))
+    }
+
+    /// Where a file was left off, in seconds
+    pub fn position(&self, path: &Path, hash: u64) -> Option<f64> {
+        self.find(path, hash).map(|i| self.positions[i].seconds)
+    }
+
+    pub fn set_position(&mut self, path: &Path, hash: u64, seconds: f64) {
+        let position = Position {
+            path: path.to_owned(),
+            hash,
+            seconds,
+        };
+        // Whatever file used to be at this path is gone
+        self.positions.retain(|p| p.path != path || p.hash == hash);
+        match self.find(path, hash) {
+            Some(i) => self.positions[i] = position,
+            None => self.positions.push(position),
+        }
+        self.save_positions();
+    }
+
+    /// Forgets the position of a file that was played to the end
+    pub fn forget(&mut self, path: &Path, hash: u64) {
+        if let Some(i) = self.find(path, hash) {
+            self.positions.remove(i);
+            self.save_positions();
+        }
+    }
+
+    pub fn bookmark(&self, name: &str) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|b| b.name == name)
+    }
+
+    pub fn set_bookmark(&mut self, name: &str, path: &Path, seconds: f64) {
+        let bookmark = Bookmark {
+            name: name.to_string(),
+            path: path.to_owned(),
+            seconds,
+        };
+        match self.bookmarks.iter().position(|b| b.name == name) {
+            Some(i) => self.bookmarks[i] = bookmark,
+            None => self.bookmarks.push(bookmark),
+        }
+        self.save_bookmarks();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> ResumeStore {
+        let position = |path: &str, hash, seconds| Position {
+            path: path.into(),
+            hash,
+            seconds,
+        };
+        ResumeStore {
+            positions: vec![position("a.flac", 1, 10.0), position("copy.flac", 1, 20.0)],
+            bookmarks: vec![],
+        }
+    }
+
+    #[test]
+    fn replaced_file_starts_over() {
+        assert_eq!(store().position(Path::new("a.flac"), 2), None);
+    }
+
+    #[test]
+    fn path_picks_between_copies() {
+        let store = store();
+        assert_eq!(store.position(Path::new("a.flac"), 1), Some(10.0));
+        assert_eq!(store.position(Path::new("copy.flac"), 1), Some(20.0));
+        // Moved files keep theirs
+        assert_eq!(store.position(Path::new("moved.flac"), 1), Some(10.0));
+    }
+}
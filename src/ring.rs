@@ -0,0 +1,216 @@
+//! A lock-free single producer, single consumer ring buffer of samples.
+//!
+//! The consumer side never blocks or allocates, so it is safe to use from the
+//! pipewire realtime thread.
+
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+const NO_CLEAR: usize = usize::MAX;
+
+struct Shared {
+    data: Box<[UnsafeCell<f32>]>,
+    /// Total samples ever read. Only written by the consumer.
+    read: AtomicUsize,
+    /// Total samples ever written. Only written by the producer.
+    write: AtomicUsize,
+    /// Write position up to which the consumer should discard samples
+    clear_to: AtomicUsize,
+    finished: AtomicBool,
+}
+
+// Safety: every slot is only accessed by one side at a time, as guarded by the
+// read and write positions.
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+pub fn ring(capacity: usize) -> (Producer, Consumer) {
+    assert!(capacity > 0, "Ring capacity must be non-zero");
+    let data = (0..capacity).map(|_| UnsafeCell::new(0.0)).collect();
+    let shared = Arc::new(Shared {
+        data,
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+        clear_to: AtomicUsize::new(NO_CLEAR),
+        finished: AtomicBool::new(false),
+    });
+
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl Producer {
+    /// Writes as many samples as fit and returns how many were written.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let shared = &self.shared;
+        let write = shared.write.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+        let free = shared.capacity() - write.wrapping_sub(read);
+        let count = free.min(samples.len());
+
+        for (i, sample) in samples[..count].iter().enumerate() {
+            let slot = write.wrapping_add(i) % shared.capacity();
+            unsafe { *shared.data[slot].get() = *sample };
+        }
+
+        shared
+            .write
+            .store(write.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Asks the consumer to drop everything written so far. Anything pushed
+    /// after this call is kept.
+    pub fn clear(&self) {
+        let write = self.shared.write.load(Ordering::Relaxed);
+        self.shared.clear_to.store(write, Ordering::Release);
+    }
+
+    /// Whether the consumer has yet to apply the last clear
+    pub fn is_clearing(&self) -> bool {
+        self.shared.clear_to.load(Ordering::Acquire) != NO_CLEAR
+    }
+
+    /// Marks whether the producer is done. The consumer will see it once it
+    /// has drained the remaining samples.
+    pub fn set_finished(&self, finished: bool) {
+        self.shared.finished.store(finished, Ordering::Release);
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.set_finished(true);
+    }
+}
+
+impl Consumer {
+    /// Applies a pending clear from the producer. Returns true if one was
+    /// applied.
+    pub fn take_clear(&mut self) -> bool {
+        let clear_to = self.shared.clear_to.swap(NO_CLEAR, Ordering::Acquire);
+        if clear_to == NO_CLEAR {
+            return false;
+        }
+
+        let read = self.shared.read.load(Ordering::Relaxed);
+        // Positions only ever grow, so only move forward
+        if clear_to.wrapping_sub(read) <= self.shared.capacity() {
+            self.shared.read.store(clear_to, Ordering::Release);
+        }
+        true
+    }
+
+    /// Reads as many samples as are available into `out` and returns how many
+    /// were read.
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let shared = &self.shared;
+        let read = shared.read.load(Ordering::Relaxed);
+        let write = shared.write.load(Ordering::Acquire);
+        let count = write.wrapping_sub(read).min(out.len());
+
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            let slot = read.wrapping_add(i) % shared.capacity();
+            *sample = unsafe { *shared.data[slot].get() };
+        }
+
+        shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Number of samples ready to be read
+    pub fn len(&self) -> usize {
+        let shared = &self.shared;
+        shared
+            .write
+            .load(Ordering::Acquire)
+            .wrapping_sub(shared.read.load(Ordering::Relaxed))
+    }
+
+    /// True once the producer is done and every sample has been read
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire) && self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = ring(4);
+        let mut out = [0.0; 3];
+        // Every round starts further into the ring until it wraps
+        for round in 0..5 {
+            let samples = [round as f32, round as f32 + 0.25, round as f32 + 0.5];
+            assert_eq!(producer.push(&samples), 3);
+            assert_eq!(consumer.pop(&mut out), 3);
+            assert_eq!(out, samples);
+        }
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let (mut producer, mut consumer) = ring(4);
+        let mut out = [0.0; 8];
+        assert_eq!(consumer.pop(&mut out), 0);
+
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0, 4.0, 5.0]), 4);
+        assert_eq!(producer.push(&[6.0]), 0);
+        assert_eq!(consumer.len(), 4);
+
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out[..4], [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(consumer.len(), 0);
+        assert_eq!(consumer.pop(&mut out), 0);
+    }
+
+    #[test]
+    fn clear_keeps_later_samples() {
+        let (mut producer, mut consumer) = ring(8);
+        producer.push(&[1.0, 2.0, 3.0]);
+        producer.clear();
+        producer.push(&[4.0, 5.0]);
+
+        assert!(consumer.take_clear());
+        assert!(!consumer.take_clear());
+        let mut out = [0.0; 8];
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out[..2], [4.0, 5.0]);
+    }
+
+    #[test]
+    fn finishes_once_drained() {
+        let (mut producer, mut consumer) = ring(4);
+        producer.push(&[1.0]);
+        drop(producer);
+        assert!(!consumer.is_finished());
+        consumer.pop(&mut [0.0; 4]);
+        assert!(consumer.is_finished());
+    }
+}
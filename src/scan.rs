@@ -0,0 +1,108 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+use crate::{
+    loudness::{Loudness, LoudnessMeter},
+    replaygain::{self, ReplayGain, REFERENCE_LOUDNESS},
+    song::{SongReader, SongReaderError},
+};
+
+/// Measures the loudness of every song under `path` and writes the results to
+/// a ReplayGain sidecar in each directory. Songs in the same directory are
+/// treated as one album.
+pub fn scan<T: AsRef<Path>>(path: T) -> Result<(), Box<dyn Error>> {
+    let mut albums: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for file in crate::handle_input_path(path)? {
+        if file.file_name().and_then(|n| n.to_str()) == Some(replaygain::SIDECAR_NAME) {
+            continue;
+        }
+        let dir = file.parent().map(Path::to_owned).unwrap_or_default();
+        albums.entry(dir).or_default().push(file);
+    }
+
+    for (dir, mut files) in albums {
+        files.sort();
+        scan_album(&dir, &files)?;
+    }
+
+    Ok(())
+}
+
+fn scan_album(dir: &Path, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let mut meters = vec![];
+    for file in files {
+        match measure(file) {
+            Ok(meter) => meters.push((file, meter)),
+            Err(e) => warn!("Failed to scan {}: {e}", file.display()),
+        }
+    }
+
+    if meters.is_empty() {
+        return Ok(());
+    }
+
+    let album = LoudnessMeter::combined(&meters.iter().map(|(_, m)| m).collect::<Vec<_>>());
+    if let Some(album) = album {
+        println!("{}: {}", dir.display(), describe(album));
+    }
+
+    let mut songs = vec![];
+    for (file, meter) in &meters {
+        let Some(track) = meter.loudness() else {
+            warn!("{} is too quiet to measure", file.display());
+            continue;
+        };
+        println!("  {}: {}", file.display(), describe(track));
+
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        songs.push((
+            name.into_owned(),
+            ReplayGain {
+                track_gain: Some(gain(track)),
+                track_peak: Some(track.peak as f32),
+                album_gain: album.map(gain),
+                album_peak: album.map(|a| a.peak as f32),
+            },
+        ));
+    }
+
+    replaygain::write_sidecar(dir, &songs)?;
+    info!("Wrote ReplayGain sidecar for {}", dir.display());
+    Ok(())
+}
+
+fn gain(loudness: Loudness) -> f32 {
+    (REFERENCE_LOUDNESS - loudness.integrated) as f32
+}
+
+fn describe(loudness: Loudness) -> String {
+    format!(
+        "{:.1} LUFS, peak {:.1} dBTP, gain {:+.2} dB",
+        loudness.integrated,
+        20.0 * loudness.peak.log10(),
+        gain(loudness)
+    )
+}
+
+/// Decodes a whole song through the loudness meter
+fn measure(path: &Path) -> Result<LoudnessMeter, Box<dyn Error>> {
+    let mut song = SongReader::open(path)?;
+    let mut meter = LoudnessMeter::new(song.rate, song.channels as usize);
+
+    loop {
+        match song.next_chunk() {
+            Ok(chunk) => meter.process(chunk),
+            Err(SongReaderError::DecodeError(e)) => warn!("Decoding error (not fatal): {e}"),
+            Err(SongReaderError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(meter)
+}
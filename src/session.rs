@@ -0,0 +1,122 @@
+//! The queue and where we are in it, saved so `--resume` can pick up after a
+//! restart. The volume is saved on its own whenever it changes.
+
+use std::{fmt::Write, path::PathBuf};
+
+use log::warn;
+
+use crate::{
+    queue::{Queue, Repeat, SharedQueue},
+    state,
+    status::Status,
+};
+
+const STATE_FILE: &str = "session";
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub entries: Vec<PathBuf>,
+    /// Index of the song that was playing
+    pub index: usize,
+    /// Position in that song, in seconds
+    pub position: f64,
+    pub shuffle: bool,
+    pub repeat: Repeat,
+}
+
+impl Session {
+    pub fn capture(queue: &Queue, position: f64) -> Self {
+        Self {
+            entries: queue.entries().to_vec(),
+            index: queue.current(),
+            position,
+            shuffle: queue.shuffle(),
+            repeat: queue.repeat(),
+        }
+    }
+
+    /// Saves the session as `key value` lines followed by one `entry` line
+    /// per song
+    pub fn save(&self) {
+        let mut saved = String::new();
+        let _ = writeln!(saved, "index {}", self.index);
+        let _ = writeln!(saved, "position {}", self.position);
+        let _ = writeln!(saved, "shuffle {}", self.shuffle);
+        let _ = writeln!(saved, "repeat {}", self.repeat);
+        for entry in &self.entries {
+            let _ = writeln!(saved, "entry {}", entry.display());
+        }
+
+        if let Err(e) = state::write(STATE_FILE, &saved) {
+            warn!("Failed to save session: {e:?}");
+        }
+    }
+
+    pub fn load() -> Option<Self> {
+        let saved = state::read(STATE_FILE)?;
+        let mut session = Self {
+            entries: vec![],
+            index: 0,
+            position: 0.0,
+            shuffle: false,
+            repeat: Repeat::Off,
+        };
+
+        for line in saved.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            let parsed = match key {
+                "index" => value.parse().map(|v| session.index = v).is_ok(),
+                "position" => value.parse().map(|v| session.position = v).is_ok(),
+                "shuffle" => value.parse().map(|v| session.shuffle = v).is_ok(),
+                "repeat" => value.parse().map(|v| session.repeat = v).is_ok(),
+                "entry" => {
+                    session.entries.push(value.into());
+                    true
+                }
+                _ => false,
+            };
+            if !parsed {
+                warn!("Ignoring malformed session line: {line:?}");
+            }
+        }
+
+        Some(session)
+    }
+
+    /// The queue to continue with and where to start its first song
+    pub fn into_queue(self) -> (Queue, Option<(PathBuf, f64)>) {
+        // A session that played to the end starts over
+        let (index, position) = if self.index < self.entries.len() {
+            (self.index, self.position)
+        } else {
+            (0, 0.0)
+        };
+
+        let start = self.entries.get(index).map(|e| (e.clone(), position));
+        let queue = Queue::restore(self.entries, index, self.shuffle, self.repeat);
+        (queue, start)
+    }
+}
+
+/// Saves the session as it is right now
+pub fn save(queue: &SharedQueue, status: &Status) {
+    Session::capture(&queue.lock().unwrap(), status.position()).save();
+}
+
+/// Saves the session after a song played to the end, so resuming starts with
+/// the one after it
+pub fn save_next(queue: &SharedQueue) {
+    let queue = queue.lock().unwrap();
+    let mut session = Session::capture(&queue, 0.0);
+    session.index = queue.upcoming();
+    session.save();
+}
+
+/// Saves a session that played everything, so resuming it starts over
+pub fn save_finished(queue: &SharedQueue) {
+    let mut session = Session::capture(&queue.lock().unwrap(), 0.0);
+    session.index = session.entries.len();
+    session.save();
+}
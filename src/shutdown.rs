@@ -0,0 +1,21 @@
+//! Cleanup that has to happen however the player exits, be it `quit`, a
+//! signal or the end of the queue.
+
+use std::sync::Mutex;
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+/// Registers something to do before exiting. Can be called from any thread.
+pub fn on_shutdown(hook: impl FnOnce() + Send + 'static) {
+    HOOKS.lock().unwrap().push(Box::new(hook));
+}
+
+/// Runs the hooks, most recently registered first
+pub fn run() {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap());
+    for hook in hooks.into_iter().rev() {
+        hook();
+    }
+}
@@ -1,20 +1,35 @@
-use std::{error::Error, fs::File, path::Path};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
 use symphonia::{
     core::{
         audio::SampleBuffer,
         codecs::Decoder,
         errors::Error as SymphoniaError,
         formats::{FormatReader, SeekMode, SeekTo},
-        io::MediaSourceStream,
-        meta::StandardTagKey,
+        io::{MediaSource, MediaSourceStream, ReadOnlySource},
+        meta::{StandardTagKey, Tag},
         probe::Hint,
-        units::Time,
+        units::{Time, TimeBase},
     },
     default,
 };
 
+use crate::{
+    chapter::{self, Chapter, VorbisChapters},
+    cue::{self, CueSheet},
+    error::PlayerError,
+    http::{self, HttpSource},
+    replaygain::{self, ReplayGain},
+};
+
 pub type SongReaderError = SymphoniaError;
 
+/// The path that stands for standard input, e.g. `pwplayer -`
+pub const STDIN: &str = "-";
+
 pub struct SongReader {
     buffer: Option<SampleBuffer<f32>>,
     pub channels: u32,
@@ -22,47 +37,235 @@ pub struct SongReader {
     pub rate: u32,
     reader: Box<dyn FormatReader>,
     track_id: u32,
+    time_base: Option<TimeBase>,
+    /// Frames decoded so far, i.e. the position of the next chunk in the file
+    position: u64,
+    /// Where in the file the song starts and ends, in frames. Only tracks of
+    /// a CUE sheet don't span the whole file.
+    start: u64,
+    end: Option<u64>,
+    /// Frames before this are decoded but dropped, since seeks land at the
+    /// start of a packet
+    skip_to: u64,
+    /// Total length in frames, if the container knows it
+    pub length: Option<u64>,
     pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub replay_gain: ReplayGain,
+    pub chapters: Vec<Chapter>,
+    /// Whether the source can seek, which pipes can't
+    seekable: bool,
+    /// What a station is playing, for streams that say
+    stream_title: Option<http::Title>,
+    /// Stops a stream's reads from waiting on its download
+    closer: Option<http::Closer>,
+}
+
+#[derive(Default)]
+struct Tags {
+    name: Option<String>,
+    artist: Option<String>,
+    /// Only used when there is no artist
+    album_artist: Option<String>,
+    album: Option<String>,
+    track: Option<u32>,
+    replay_gain: ReplayGain,
+    chapters: VorbisChapters,
+}
+
+impl Tags {
+    fn read(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.name = Some(tag.value.to_string()),
+                Some(StandardTagKey::Artist) => self.artist = Some(tag.value.to_string()),
+                Some(StandardTagKey::AlbumArtist) => {
+                    self.album_artist = Some(tag.value.to_string())
+                }
+                Some(StandardTagKey::Album) => self.album = Some(tag.value.to_string()),
+                Some(StandardTagKey::TrackNumber) => {
+                    // Track numbers are often written as "3/12"
+                    let value = tag.value.to_string();
+                    self.track = value.split('/').next().and_then(|n| n.trim().parse().ok());
+                }
+                Some(key) => self.replay_gain.read_tag(key, &tag.value.to_string()),
+                None => {
+                    self.chapters.read_tag(&tag.key, &tag.value.to_string());
+                }
+            }
+        }
+    }
 }
 
 impl SongReader {
-    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, Box<dyn Error>> {
+    /// Opens the song at `path`, reading standard input for `-` and streaming
+    /// URLs
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Self, PlayerError> {
+        if path.as_ref() == Path::new(STDIN) {
+            Self::from_reader(io::stdin())
+        } else if http::is_url(path.as_ref()) {
+            Self::from_url(&path.as_ref().to_string_lossy())
+        } else if let Some((sheet, number)) = cue::split(path.as_ref()) {
+            Self::from_cue(path.as_ref(), &sheet, number)
+        } else {
+            Self::from_file(path)
+        }
+    }
+
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, PlayerError> {
+        let file = File::open(path.as_ref())?;
+        let mut song = Self::from_source(Box::new(file), Hint::new(), Some(path.as_ref()))?;
+        if song.chapters.is_empty() {
+            song.chapters = chapter::read_container(path.as_ref());
+        }
+        Ok(song)
+    }
+
+    /// Opens a track of a CUE sheet. `path` is its queue entry, which its
+    /// ReplayGain is looked up by.
+    fn from_cue(path: &Path, sheet: &Path, number: u32) -> Result<Self, PlayerError> {
+        let sheet = CueSheet::read(sheet)?;
+        let track = sheet.track(number).ok_or_else(|| {
+            PlayerError::UnsupportedFormat(format!("The CUE sheet has no track {number}"))
+        })?;
+
+        let mut hint = Hint::new();
+        if let Some(extension) = track.file.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+        let file = File::open(&track.file)?;
+        let mut song = Self::from_source(Box::new(file), hint, Some(path))?;
+        if song.chapters.is_empty() {
+            song.chapters = chapter::read_container(&track.file);
+        }
+
+        let rate = song.rate as f64;
+        song.start = (track.start * rate).round() as u64;
+        song.end = track.end.map(|end| (end * rate).round() as u64);
+        song.length = song
+            .end
+            .or(song.length)
+            .map(|end| end.saturating_sub(song.start));
+        song.name = track.title.clone().or(song.name);
+        song.artist = track
+            .performer
+            .clone()
+            .or(sheet.performer.clone())
+            .or(song.artist);
+        song.album = sheet.title.clone().or(song.album);
+        song.track = Some(number);
+        // Only the chapters within the track, counted from its start
+        song.chapters = std::mem::take(&mut song.chapters)
+            .into_iter()
+            .filter(|c| c.start >= track.start && track.end.is_none_or(|end| c.start < end))
+            .map(|c| Chapter {
+                start: c.start - track.start,
+                ..c
+            })
+            .collect();
+        if song.start > 0 {
+            song.seek_time(Time::from(0.0))?;
+        }
+        Ok(song)
+    }
+
+    pub fn from_url(url: &str) -> Result<Self, PlayerError> {
+        let source = HttpSource::open(url)?;
+        let mut hint = Hint::new();
+        if let Some(extension) = source.extension() {
+            hint.with_extension(extension);
+        }
+        let name = source.name.clone();
+        let title = source.title.clone();
+        let closer = source.closer();
+
+        let mut song = Self::from_source(Box::new(source), hint, None)?;
+        // Radio stations rarely tag the stream itself
+        song.name = song.name.or(name);
+        song.stream_title = Some(title);
+        song.closer = Some(closer);
+        Ok(song)
+    }
+
+    /// Reads a song from a source that can't seek, e.g. a pipe
+    pub fn from_reader<R: Read + Send + Sync + 'static>(reader: R) -> Result<Self, PlayerError> {
+        Self::from_source(Box::new(ReadOnlySource::new(reader)), Hint::new(), None)
+    }
+
+    /// `path` is where to look for a ReplayGain sidecar
+    fn from_source(
+        source: Box<dyn MediaSource>,
+        hint: Hint,
+        path: Option<&Path>,
+    ) -> Result<Self, PlayerError> {
         let codecs = default::get_codecs();
         let probe = default::get_probe();
 
-        let stream =
-            MediaSourceStream::new(Box::new(File::open(path.as_ref())?), Default::default());
-        let mut probed = probe.format(
-            &Hint::default(),
-            stream,
-            &Default::default(),
-            &Default::default(),
-        )?;
+        let seekable = source.is_seekable();
+        let stream = MediaSourceStream::new(source, Default::default());
+        let mut probed = probe.format(&hint, stream, &Default::default(), &Default::default())?;
 
-        let name = if let Some(md) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
-            let mut name = None;
-            for (_i, tag) in md.tags().iter().enumerate() {
-                let _ = tag.std_key.and_then(|k| {
-                    if k == StandardTagKey::TrackTitle {
-                        name = Some(tag.value.to_string());
-                    }
-                    Some(())
-                });
-            }
-            name
-        } else {
-            None
-        };
+        let mut tags = Tags::default();
+        if let Some(md) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            tags.read(md.tags());
+        }
+        // Some formats (e.g. FLAC) keep their tags in the container instead
+        if let Some(md) = probed.format.metadata().current() {
+            tags.read(md.tags());
+        }
+
+        if let Some(path) = path.filter(|_| tags.replay_gain.is_empty()) {
+            tags.replay_gain = replaygain::read_sidecar(path).unwrap_or_default();
+        }
 
         let reader = probed.format;
 
-        let track = reader.default_track().ok_or("File has no tracks")?;
+        let unsupported = |what: &str| PlayerError::UnsupportedFormat(what.to_string());
+        let track = reader
+            .default_track()
+            .ok_or_else(|| unsupported("File has no tracks"))?;
         let decoder = codecs.make(&track.codec_params, &Default::default())?;
         let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let length = track.codec_params.n_frames;
 
         let params = &track.codec_params;
-        let channels = params.channels.as_ref().ok_or("No channel data")?.count() as u32;
-        let rate = params.sample_rate.ok_or("No sample rate")?;
+        let channels = params
+            .channels
+            .as_ref()
+            .ok_or_else(|| unsupported("No channel data"))?
+            .count() as u32;
+        let rate = params
+            .sample_rate
+            .ok_or_else(|| unsupported("No sample rate"))?;
+
+        // A FLAC cuesheet marks chapters too, along with where the audio ends
+        let mut chapters: Vec<Chapter> = reader
+            .cues()
+            .iter()
+            .filter(|cue| length.is_none_or(|length| cue.start_ts < length))
+            .map(|cue| {
+                // The points are the track's indices, and the gap before a
+                // track comes first when it has one
+                let index = cue.points.get(1).or(cue.points.first());
+                let start = cue.start_ts + index.map_or(0, |p| p.start_offset_ts);
+                let title = cue
+                    .tags
+                    .iter()
+                    .find(|t| t.std_key == Some(StandardTagKey::TrackTitle))
+                    .map(|t| t.value.to_string());
+                Chapter {
+                    title,
+                    start: start as f64 / rate as f64,
+                }
+            })
+            .collect();
+        if chapters.is_empty() {
+            chapters = tags.chapters.into_chapters();
+        }
+        chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
 
         Ok(Self {
             buffer: None,
@@ -71,42 +274,200 @@ impl SongReader {
             rate,
             reader,
             track_id,
-            name,
+            time_base,
+            position: 0,
+            start: 0,
+            end: None,
+            skip_to: 0,
+            length,
+            name: tags.name,
+            artist: tags.artist.or(tags.album_artist),
+            album: tags.album,
+            track: tags.track,
+            replay_gain: tags.replay_gain,
+            chapters,
+            seekable,
+            stream_title: None,
+            closer: None,
         })
     }
 
-    pub fn next_chunk(&mut self) -> Result<&SampleBuffer<f32>, SymphoniaError> {
-        let packet = match self.reader.next_packet() {
-            Ok(p) => p,
-            Err(SymphoniaError::ResetRequired) => {
-                self.decoder.reset();
-                return self.next_chunk();
+    pub fn next_chunk(&mut self) -> Result<&[f32], SymphoniaError> {
+        loop {
+            if self.end.is_some_and(|end| self.position >= end) {
+                let e = io::Error::new(io::ErrorKind::UnexpectedEof, "End of track");
+                return Err(SymphoniaError::IoError(e));
             }
-            Err(e) => return Err(e),
-        };
 
-        let decoded = self.decoder.decode(&packet)?;
+            let packet = match self.reader.next_packet() {
+                Ok(p) => p,
+                Err(SymphoniaError::ResetRequired) => {
+                    self.decoder.reset();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
-        if self.buffer.is_none() {
-            let buffer = SampleBuffer::new(decoded.capacity() as u64, *decoded.spec());
-            let _ = self.buffer.replace(buffer);
-        }
+            let decoded = self.decoder.decode(&packet)?;
+            let first = self.position;
+            let frames = decoded.frames() as u64;
+            self.position += frames;
+
+            // Only the part of the packet that belongs to the song
+            let from = self
+                .start
+                .max(self.skip_to)
+                .saturating_sub(first)
+                .min(frames) as usize;
+            let to = self
+                .end
+                .map_or(frames, |end| end.saturating_sub(first).min(frames))
+                as usize;
+            if from >= to && frames > 0 {
+                continue;
+            }
+
+            if self.buffer.is_none() {
+                let buffer = SampleBuffer::new(decoded.capacity() as u64, *decoded.spec());
+                let _ = self.buffer.replace(buffer);
+            }
 
-        let buffer = self.buffer.as_mut().unwrap();
-        buffer.copy_interleaved_ref(decoded);
+            let buffer = self.buffer.as_mut().unwrap();
+            buffer.copy_interleaved_ref(decoded);
 
-        Ok(buffer)
+            let channels = self.channels as usize;
+            return Ok(&buffer.samples()[from * channels..to * channels]);
+        }
     }
 
-    pub fn seek_time(&mut self, time: Time) -> Result<(), Box<dyn Error>> {
-        self.reader.seek(
-            SeekMode::Coarse,
+    /// Seeks to `time` and returns the time that was actually reached, which
+    /// is only earlier past the end of the song.
+    pub fn seek_time(&mut self, time: Time) -> Result<Time, PlayerError> {
+        if !self.seekable {
+            return Err(unseekable());
+        }
+        // Times are in the song, which may start later in the file. Rounding
+        // up keeps a seek to e.g. a chapter from landing just before it.
+        let seconds = time.seconds as f64 + time.frac;
+        let target = self.start + (seconds * self.rate as f64).ceil() as u64;
+        let start = self.start as f64 / self.rate as f64;
+        let time = Time::from(seconds + start);
+        let seeked = self.reader.seek(
+            SeekMode::Accurate,
             SeekTo::Time {
                 time,
                 track_id: Some(self.track_id),
             },
         )?;
+        self.decoder.reset();
+
+        let actual = self
+            .time_base
+            .map(|tb| tb.calc_time(seeked.actual_ts))
+            .unwrap_or(time);
+        let actual = actual.seconds as f64 + actual.frac;
+        self.position = (actual * self.rate as f64).round() as u64;
+        // The packet the seek landed in starts before the target, and
+        // `next_chunk` skips up to it
+        self.skip_to = target.max(self.position);
+        Ok(Time::from(self.position() as f64 / self.rate as f64))
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    /// What stops a stream this reads from, for another thread to use
+    pub fn closer(&self) -> Option<http::Closer> {
+        self.closer.clone()
+    }
+
+    /// The title a stream started playing since this was last called
+    pub fn title_change(&self) -> Option<String> {
+        self.stream_title.as_ref()?.lock().unwrap().take()
+    }
+
+    /// Position of the next chunk, in frames
+    pub fn position(&self) -> u64 {
+        self.position.max(self.skip_to).saturating_sub(self.start)
+    }
+}
+
+/// What seeking in a song that can't seek fails with
+pub fn unseekable() -> PlayerError {
+    PlayerError::UnsupportedFormat("Can't seek in a song read from a pipe".to_string())
+}
+
+/// Writes a stereo 16-bit WAV of a sine sweep for tests and returns its path.
+/// `name` may include directories.
+#[cfg(test)]
+pub fn test_song(name: &str, rate: u32, frames: usize) -> std::path::PathBuf {
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("pwplayer-test-{}", std::process::id()));
+    let path = dir.join(name);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    let data_size = (frames * 4) as u32;
+    let mut wav = vec![];
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&rate.to_le_bytes());
+    wav.extend_from_slice(&(rate * 4).to_le_bytes());
+    wav.extend_from_slice(&4u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for i in 0..frames {
+        // Rising in pitch, so any jump or repeat changes the samples
+        let t = i as f32 / rate as f32;
+        let sample = ((t * (200.0 + 400.0 * t) * std::f32::consts::TAU).sin() * 16000.0) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+        wav.extend_from_slice(&(-sample).to_le_bytes());
+    }
+    File::create(&path).unwrap().write_all(&wav).unwrap();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chapter::ChapterChange, flac::FlacWriter};
+
+    /// A frame's sample, which 24-bit FLAC keeps exactly
+    fn sample(frame: usize) -> f32 {
+        (frame % 100_000) as f32 / (1 << 23) as f32
+    }
+
+    #[test]
+    fn seeks_exactly_into_a_packet() {
+        let path = std::env::temp_dir().join(format!("pwplayer-seek-{}.flac", std::process::id()));
+        let mut writer = FlacWriter::create(&path, 44100).unwrap();
+        let samples: Vec<f32> = (0..44100 * 3)
+            .flat_map(|i| [sample(i), -sample(i)])
+            .collect();
+        writer.write(&samples).unwrap();
+        writer.finish().unwrap();
+
+        let mut song = SongReader::open(&path).unwrap();
+        let reached = song.seek_time(Time::from(1.5)).unwrap();
+        assert_eq!(reached.seconds as f64 + reached.frac, 1.5);
+        assert_eq!(song.position(), 66150);
+        let chunk = song.next_chunk().unwrap();
+        assert_eq!(
+            chunk[..4],
+            [sample(66150), -sample(66150), sample(66151), -sample(66151)]
+        );
 
-        Ok(())
+        // So moving on a chapter from there lands in the next one
+        let chapters = [0.0, 1.5, 2.5].map(|start| Chapter { title: None, start });
+        let position = song.position() as f64 / song.rate as f64;
+        let next = chapter::target(&chapters, position, ChapterChange::Next).unwrap();
+        assert_eq!(next, 2.5);
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,43 @@
+//! Small files that keep player state between runs, stored under
+//! `$XDG_STATE_HOME/pwplayer` (or `~/.local/state/pwplayer`).
+
+use std::{env, path::PathBuf, time::SystemTime};
+
+/// `$var/pwplayer`, or `~/fallback/pwplayer` when the variable is unset
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = match env::var_os(var).filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(fallback),
+    };
+    Some(base.join("pwplayer"))
+}
+
+fn dir() -> Option<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// Where user written configuration lives, `$XDG_CONFIG_HOME/pwplayer`
+pub fn config_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Reads a state file, None if it doesn't exist yet
+pub fn read(name: &str) -> Option<String> {
+    std::fs::read_to_string(dir()?.join(name)).ok()
+}
+
+/// When a state file was last written, None if it doesn't exist yet
+pub fn modified(name: &str) -> Option<SystemTime> {
+    std::fs::metadata(dir()?.join(name)).ok()?.modified().ok()
+}
+
+/// Replaces a state file, creating the state directory if needed
+pub fn write(name: &str, contents: &str) -> std::io::Result<()> {
+    let dir = dir().ok_or_else(|| std::io::Error::other("No state directory"))?;
+    std::fs::create_dir_all(&dir)?;
+
+    // Write a temporary file first so a crash never leaves a half written one
+    let tmp = dir.join(format!(".{name}.tmp"));
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, dir.join(name))
+}
@@ -0,0 +1,176 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    chapter::{self, Chapter},
+    volume::{Volume, CHANNELS},
+};
+
+/// Playback state shared between the pipewire thread, the decoder and the
+/// command thread. Everything touched from the realtime thread is atomic.
+#[derive(Default)]
+pub struct Status {
+    title: Mutex<Option<String>>,
+    chapters: Mutex<Vec<Chapter>>,
+    rate: AtomicU32,
+    /// Position in the song, in frames of the song, that `frames` counts from
+    base: AtomicU64,
+    /// Frames played since `base`. These cover `speed` frames of the song each.
+    frames: AtomicU64,
+    /// Bits of the playback speed as an f32
+    speed: AtomicU32,
+    /// Position to jump to once the realtime thread drops pre-seek samples
+    seek_target: AtomicU64,
+    /// Bits of the speed the samples after the seek were made at
+    seek_speed: AtomicU32,
+    /// Underruns over the whole session
+    underruns: AtomicU64,
+    /// Underruns in the current song
+    song_underruns: AtomicU64,
+    /// Bits of the volume level as an f32
+    volume: AtomicU32,
+    muted: AtomicBool,
+    /// Bits of the balance and channel levels, only read when reporting
+    balance: AtomicU32,
+    channels: [AtomicU32; CHANNELS],
+    /// Set by the decoder when a broken song should stop the queue
+    queue_stopped: AtomicBool,
+}
+
+pub type SharedStatus = Arc<Status>;
+
+impl Status {
+    pub fn start_song(&self, title: Option<String>, rate: u32, frames: u64, speed: f32) {
+        *self.title.lock().unwrap() = title;
+        self.rate.store(rate, Ordering::Relaxed);
+        self.base.store(frames, Ordering::Relaxed);
+        self.frames.store(0, Ordering::Relaxed);
+        self.speed.store(speed.to_bits(), Ordering::Relaxed);
+        self.song_underruns.store(0, Ordering::Relaxed);
+    }
+
+    /// For streams that tell what they are playing as they go
+    pub fn set_title(&self, title: String) {
+        *self.title.lock().unwrap() = Some(title);
+    }
+
+    pub fn set_chapters(&self, chapters: Vec<Chapter>) {
+        *self.chapters.lock().unwrap() = chapters;
+    }
+
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.chapters.lock().unwrap().clone()
+    }
+
+    /// The chapter playing right now and its index
+    pub fn chapter(&self) -> Option<(usize, Chapter)> {
+        let chapters = self.chapters.lock().unwrap();
+        let index = chapter::current(&chapters, self.position())?;
+        Some((index, chapters[index].clone()))
+    }
+
+    /// Nothing is playing until the next `start_song`
+    pub fn stop(&self) {
+        *self.title.lock().unwrap() = None;
+        self.chapters.lock().unwrap().clear();
+        self.rate.store(0, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, frames: u64) {
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn set_seek_target(&self, frames: u64, speed: f32) {
+        self.seek_speed.store(speed.to_bits(), Ordering::Relaxed);
+        self.seek_target.store(frames, Ordering::Release);
+    }
+
+    /// Called from the realtime thread once the samples from before a seek
+    /// have been dropped.
+    pub fn finish_seek(&self) {
+        let frames = self.seek_target.load(Ordering::Acquire);
+        self.base.store(frames, Ordering::Relaxed);
+        self.frames.store(0, Ordering::Relaxed);
+        let speed = self.seek_speed.load(Ordering::Relaxed);
+        self.speed.store(speed, Ordering::Relaxed);
+    }
+
+    pub fn set_volume(&self, volume: Volume) {
+        self.volume.store(volume.level.to_bits(), Ordering::Relaxed);
+        self.muted.store(volume.muted, Ordering::Relaxed);
+        self.balance
+            .store(volume.balance.to_bits(), Ordering::Relaxed);
+        for (slot, level) in self.channels.iter().zip(volume.channels) {
+            slot.store(level.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Asks for no more songs to be played after the current one
+    pub fn stop_queue(&self) {
+        self.queue_stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `stop_queue` was called since the last time this was checked
+    pub fn take_queue_stopped(&self) -> bool {
+        self.queue_stopped.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+        self.song_underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn speed(&self) -> f32 {
+        f32::from_bits(self.speed.load(Ordering::Relaxed))
+    }
+
+    /// Position in the current song, in seconds
+    pub fn position(&self) -> f64 {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return 0.0;
+        }
+        let played = self.frames.load(Ordering::Relaxed) as f64 * self.speed() as f64;
+        (self.base.load(Ordering::Relaxed) as f64 + played) / rate as f64
+    }
+
+    /// Formats the status as `key: value` lines for clients
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let title = self.title.lock().unwrap();
+        let _ = writeln!(report, "title: {}", title.as_deref().unwrap_or(""));
+        if let Some((index, chapter)) = self.chapter() {
+            let title = chapter.title.as_deref().unwrap_or("");
+            let _ = writeln!(report, "chapter: {} {title}", index + 1);
+        }
+        let _ = writeln!(report, "position: {:.2}", self.position());
+        let _ = writeln!(report, "speed: {}", self.speed());
+        let volume = f32::from_bits(self.volume.load(Ordering::Relaxed));
+        let _ = writeln!(report, "volume: {:.0}", volume * 100.0);
+        let _ = writeln!(report, "muted: {}", self.muted.load(Ordering::Relaxed));
+        let balance = f32::from_bits(self.balance.load(Ordering::Relaxed));
+        let _ = writeln!(report, "balance: {:.0}", balance * 100.0);
+        let channels: Vec<String> = self
+            .channels
+            .iter()
+            .map(|c| format!("{:.0}", f32::from_bits(c.load(Ordering::Relaxed)) * 100.0))
+            .collect();
+        let _ = writeln!(report, "channel_volumes: {}", channels.join(" "));
+        let _ = writeln!(
+            report,
+            "underruns: {}",
+            self.underruns.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            report,
+            "song_underruns: {}",
+            self.song_underruns.load(Ordering::Relaxed)
+        );
+        report
+    }
+}
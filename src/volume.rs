@@ -0,0 +1,158 @@
+use std::{fmt::Display, str::FromStr};
+
+use log::warn;
+
+use crate::state;
+
+const STATE_FILE: &str = "volume";
+/// Streams are always stereo for now
+pub const CHANNELS: usize = 2;
+/// Range of the dB curve when none is given
+const DEFAULT_DB_RANGE: f32 = 60.0;
+
+/// How the volume level maps onto the gain applied to the stream
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum VolumeCurve {
+    /// Cube the level because https://bugzilla.redhat.com/show_bug.cgi?id=502057
+    #[default]
+    Cubic,
+    Linear,
+    /// Spreads the level evenly over this many dB below full volume
+    Db(f32),
+}
+
+impl VolumeCurve {
+    pub fn gain(&self, level: f32) -> f32 {
+        match *self {
+            Self::Cubic => level * level * level,
+            Self::Linear => level,
+            Self::Db(_) if level <= 0.0 => 0.0,
+            Self::Db(range) => 10f32.powf((level - 1.0) * range / 20.0),
+        }
+    }
+}
+
+impl FromStr for VolumeCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "cubic" => Ok(Self::Cubic),
+            None if s == "linear" => Ok(Self::Linear),
+            None if s == "db" => Ok(Self::Db(DEFAULT_DB_RANGE)),
+            Some(("db", range)) => match range.parse::<f32>() {
+                Ok(range) if range > 0.0 => Ok(Self::Db(range)),
+                _ => Err(format!("Invalid dB range: {range}")),
+            },
+            _ => Err(format!("Unknown volume curve: {s}")),
+        }
+    }
+}
+
+impl Display for VolumeCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cubic => write!(f, "cubic"),
+            Self::Linear => write!(f, "linear"),
+            Self::Db(range) => write!(f, "db:{range}"),
+        }
+    }
+}
+
+/// A change requested by a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeChange {
+    Set(f32),
+    Adjust(f32),
+    Mute(bool),
+    /// From -1 (left only) to 1 (right only)
+    Balance(f32),
+    /// Level of a single channel, on top of the master level
+    Channel(usize, f32),
+}
+
+/// The volume the user picked, independent of the curve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Volume {
+    /// From 0 to 1
+    pub level: f32,
+    pub muted: bool,
+    pub balance: f32,
+    /// Per channel levels from 0 to 1
+    pub channels: [f32; CHANNELS],
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            level: 1.0,
+            muted: false,
+            balance: 0.0,
+            channels: [1.0; CHANNELS],
+        }
+    }
+}
+
+impl Volume {
+    pub fn apply(&mut self, change: VolumeChange) {
+        match change {
+            VolumeChange::Set(level) => self.level = level.clamp(0.0, 1.0),
+            VolumeChange::Adjust(delta) => self.level = (self.level + delta).clamp(0.0, 1.0),
+            VolumeChange::Mute(muted) => self.muted = muted,
+            VolumeChange::Balance(balance) => self.balance = balance.clamp(-1.0, 1.0),
+            VolumeChange::Channel(channel, level) => match self.channels.get_mut(channel) {
+                Some(slot) => *slot = level.clamp(0.0, 1.0),
+                None => warn!("No channel {channel} to set the volume of"),
+            },
+        }
+    }
+
+    /// The gain to apply to each channel of the stream
+    pub fn gains(&self, curve: VolumeCurve) -> [f32; CHANNELS] {
+        if self.muted {
+            return [0.0; CHANNELS];
+        }
+
+        // Balance only ever turns the opposite side down
+        let balance = [(1.0 - self.balance).min(1.0), (1.0 + self.balance).min(1.0)];
+        std::array::from_fn(|i| curve.gain(self.level * self.channels[i] * balance[i]))
+    }
+
+    /// The volume saved by the last run, or full volume
+    pub fn load() -> Self {
+        let Some(saved) = state::read(STATE_FILE) else {
+            return Self::default();
+        };
+
+        Self::parse(&saved).unwrap_or_else(|| {
+            warn!("Ignoring malformed volume state: {saved:?}");
+            Self::default()
+        })
+    }
+
+    fn parse(saved: &str) -> Option<Self> {
+        let mut fields = saved.split_whitespace();
+        let mut volume = Self {
+            level: fields.next()?.parse().ok()?,
+            muted: fields.next()?.parse().ok()?,
+            balance: fields.next()?.parse().ok()?,
+            ..Self::default()
+        };
+        for channel in volume.channels.iter_mut() {
+            *channel = fields.next()?.parse().ok()?;
+        }
+        Some(volume)
+    }
+
+    pub fn save(&self) {
+        let mut saved = format!("{} {} {}", self.level, self.muted, self.balance);
+        for channel in self.channels {
+            saved += &format!(" {channel}");
+        }
+        saved.push('\n');
+
+        if let Err(e) = state::write(STATE_FILE, &saved) {
+            warn!("Failed to save volume: {e:?}");
+        }
+    }
+}